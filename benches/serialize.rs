@@ -0,0 +1,73 @@
+//! Compares serializing a response into one contiguous buffer with writing a
+//! reused head buffer and the body as separate slices of one vectored write.
+//! Both go to a socket drained by another thread, so the kernel copy is paid
+//! either way and the difference is the copy in user space.
+//!
+//! Run with `cargo bench --bench serialize`.
+
+use std::hint::black_box;
+use std::io::{IoSlice, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use codecrafters_http_server::{Response, ResponseBody};
+
+const ROUNDS: Duration = Duration::from_millis(500);
+
+fn throughput(name: &str, size: usize, mut f: impl FnMut() -> usize) {
+    let start = Instant::now();
+    let (mut bytes, mut responses) = (0, 0);
+    while start.elapsed() < ROUNDS {
+        bytes += f();
+        responses += 1;
+    }
+    let secs = start.elapsed().as_secs_f64();
+    println!(
+        "{name:>10} {size:>8} B body: {:>10.0} responses/s {:>10.1} MB/s",
+        responses as f64 / secs,
+        bytes as f64 / secs / 1_000_000.0
+    );
+}
+
+// Like `write_all`, for the two slices.
+fn write_all_vectored(out: &mut UnixStream, mut head: &[u8], mut body: &[u8]) -> usize {
+    let total = head.len() + body.len();
+    while !head.is_empty() || !body.is_empty() {
+        let n = out
+            .write_vectored(&[IoSlice::new(head), IoSlice::new(body)])
+            .expect("write");
+        let from_head = n.min(head.len());
+        head = &head[from_head..];
+        body = &body[n - from_head..];
+    }
+    total
+}
+
+fn main() {
+    let (mut out, mut drain) = UnixStream::pair().expect("socket pair");
+    thread::spawn(move || {
+        let mut buf = vec![0; 1024 * 1024];
+        while drain.read(&mut buf).is_ok_and(|n| n > 0) {}
+    });
+    for size in [128, 16 * 1024, 1024 * 1024] {
+        let resp = Response::ok_bin(&vec![b'x'; size]).expect("response");
+
+        throughput("contiguous", size, || {
+            let bytes: Vec<u8> = resp.clone().into();
+            out.write_all(&bytes).expect("write");
+            black_box(bytes.len())
+        });
+
+        let mut head = Vec::new();
+        throughput("vectored", size, || {
+            let resp = resp.clone();
+            resp.write_head(&mut head);
+            let body = match &resp.2 {
+                Some(ResponseBody::Full(body)) => body.as_ref(),
+                _ => &[],
+            };
+            black_box(write_all_vectored(&mut out, &head, body))
+        });
+    }
+}
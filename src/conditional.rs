@@ -0,0 +1,191 @@
+use crate::{ETag, Headers, HttpDate, HttpMethod, IfRange};
+
+/// What the server currently knows about a representation.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Validators {
+    pub etag: Option<ETag>,
+    pub last_modified: Option<HttpDate>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precondition {
+    Proceed,
+    NotModified,
+    Failed,
+}
+
+/// Evaluates preconditions in the order of RFC 9110, 13.2.2. `If-Match`
+/// wins over `If-Unmodified-Since`, and `If-None-Match` over
+/// `If-Modified-Since`, which only applies to GET and HEAD. A resource that
+/// does not exist is described by empty `Validators`, so `If-None-Match: *`
+/// passes and `If-Match: *` fails for it.
+pub fn evaluate_preconditions(
+    method: HttpMethod,
+    headers: &Headers,
+    validators: &Validators,
+) -> Precondition {
+    let unchanged = match (headers.if_match(), headers.if_unmodified_since()) {
+        (Some(tags), _) => tags.matches(validators.etag.as_ref(), ETag::strong_eq),
+        (None, Some(since)) => validators
+            .last_modified
+            .map_or(true, |modified| modified <= since),
+        (None, None) => true,
+    };
+    if !unchanged {
+        return Precondition::Failed;
+    }
+    let safe = method.is_get_or_head();
+    match (headers.if_none_match(), headers.if_modified_since()) {
+        (Some(tags), _) if tags.matches(validators.etag.as_ref(), ETag::weak_eq) => {
+            if safe {
+                Precondition::NotModified
+            } else {
+                Precondition::Failed
+            }
+        }
+        (Some(_), _) => Precondition::Proceed,
+        (None, Some(since)) if safe => match validators.last_modified {
+            Some(modified) if modified <= since => Precondition::NotModified,
+            _ => Precondition::Proceed,
+        },
+        _ => Precondition::Proceed,
+    }
+}
+
+/// Whether a range request may be answered with part of the representation.
+/// `If-Range` needs a strong match: either the same strong tag or the exact
+/// modification date. Without `If-Range` the range always applies.
+pub fn if_range_applies(headers: &Headers, validators: &Validators) -> bool {
+    match headers.if_range() {
+        None => true,
+        Some(IfRange::ETag(tag)) => validators
+            .etag
+            .as_ref()
+            .is_some_and(|etag| etag.strong_eq(&tag)),
+        Some(IfRange::Date(date)) => validators.last_modified == Some(date),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EntityTags, Header};
+
+    fn validators() -> Validators {
+        Validators {
+            etag: Some(ETag::strong("abc")),
+            last_modified: Some(HttpDate::parse("Sun, 06 Nov 1994 08:49:37 GMT").unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let matching: Headers = vec![Header::if_none_match(EntityTags::Tags(vec![ETag::weak(
+            "abc",
+        )]))]
+        .into();
+        let other: Headers = vec![Header::if_none_match(EntityTags::Tags(vec![ETag::strong(
+            "def",
+        )]))]
+        .into();
+        assert_eq!(
+            evaluate_preconditions(HttpMethod::Get, &matching, &validators()),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            evaluate_preconditions(HttpMethod::Get, &other, &validators()),
+            Precondition::Proceed
+        );
+    }
+
+    #[test]
+    fn test_if_range() {
+        let if_range = |v: IfRange| -> Headers { vec![Header::if_range(v)].into() };
+        let v = validators();
+        let modified = v.last_modified.unwrap();
+        assert!(if_range_applies(&vec![].into(), &v));
+        assert!(if_range_applies(
+            &if_range(IfRange::ETag(ETag::strong("abc"))),
+            &v
+        ));
+        assert!(!if_range_applies(
+            &if_range(IfRange::ETag(ETag::weak("abc"))),
+            &v
+        ));
+        assert!(!if_range_applies(
+            &if_range(IfRange::ETag(ETag::strong("def"))),
+            &v
+        ));
+        assert!(if_range_applies(&if_range(IfRange::Date(modified)), &v));
+        assert!(!if_range_applies(
+            &if_range(IfRange::Date(modified)),
+            &Validators::default()
+        ));
+    }
+
+    #[test]
+    fn test_if_modified_since() -> crate::Result<()> {
+        let since = |d: &str| -> crate::Result<Headers> {
+            Ok(vec![Header::if_modified_since(HttpDate::parse(d)?)].into())
+        };
+        assert_eq!(
+            evaluate_preconditions(
+                HttpMethod::Get,
+                &since("Sun, 06 Nov 1994 08:49:37 GMT")?,
+                &validators()
+            ),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            evaluate_preconditions(
+                HttpMethod::Get,
+                &since("Sat, 05 Nov 1994 08:49:37 GMT")?,
+                &validators()
+            ),
+            Precondition::Proceed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_preconditions() -> crate::Result<()> {
+        let headers = |h: Header| -> Headers { vec![h].into() };
+        let missing = Validators::default();
+        let any = || EntityTags::Any;
+        assert_eq!(
+            evaluate_preconditions(HttpMethod::Put, &headers(Header::if_match(any())), &missing),
+            Precondition::Failed
+        );
+        assert_eq!(
+            evaluate_preconditions(
+                HttpMethod::Put,
+                &headers(Header::if_none_match(any())),
+                &missing
+            ),
+            Precondition::Proceed
+        );
+        assert_eq!(
+            evaluate_preconditions(
+                HttpMethod::Put,
+                &headers(Header::if_none_match(any())),
+                &validators()
+            ),
+            Precondition::Failed
+        );
+        let weak = Header::if_match(EntityTags::Tags(vec![ETag::weak("abc")]));
+        assert_eq!(
+            evaluate_preconditions(HttpMethod::Post, &headers(weak), &validators()),
+            Precondition::Failed
+        );
+        let earlier = HttpDate::parse("Sat, 05 Nov 1994 08:49:37 GMT")?;
+        assert_eq!(
+            evaluate_preconditions(
+                HttpMethod::Put,
+                &headers(Header::if_unmodified_since(earlier)),
+                &validators()
+            ),
+            Precondition::Failed
+        );
+        Ok(())
+    }
+}
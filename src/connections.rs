@@ -0,0 +1,255 @@
+use crate::{mk_response, Connection, Header, StatusCode};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+
+/// Where a request came in, for endpoints that care who is calling.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    /// Both addresses are `None` for streams that are not sockets.
+    pub remote_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    /// Unique for the life of the process.
+    pub id: u64,
+    /// Counts the requests on this connection, from 0.
+    pub request_index: u64,
+    pub tls: Option<TlsInfo>,
+    /// Who is on the other end of a Unix socket, which has no address.
+    pub peer_credentials: Option<PeerCredentials>,
+}
+
+impl ConnectionInfo {
+    /// A new connection, with the next id.
+    pub fn new(remote_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ConnectionInfo {
+            remote_addr,
+            local_addr,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            request_index: 0,
+            tls: None,
+            peer_credentials: None,
+        }
+    }
+}
+
+/// The process on the other end of a Unix socket, as `SO_PEERCRED` gives it
+/// at connect time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Not every platform reports it.
+    pub pid: Option<i32>,
+}
+
+/// What was negotiated on a TLS connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsInfo {
+    pub version: String,
+    pub cipher_suite: String,
+    pub alpn_protocol: Option<Vec<u8>>,
+    pub server_name: Option<String>,
+    /// The verified client certificate chain in DER, its own certificate
+    /// first. Empty if the client did not show one.
+    pub peer_certificates: Vec<Vec<u8>>,
+}
+
+/// What happens to connections past `max_connections`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overload {
+    /// Stop accepting until a connection closes. Clients wait in the
+    /// listen backlog.
+    PauseAccept,
+    /// Accept and answer `503` with `Retry-After`. TLS connections are
+    /// closed instead, as answering would take a handshake first.
+    Reject,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Tracks the open connections of a server. Clones share the counts, so one
+/// can be kept for monitoring while the server runs.
+#[derive(Debug, Clone)]
+pub struct ConnectionCounts {
+    max: usize,
+    per_ip_max: Option<usize>,
+    slots: Arc<Semaphore>,
+    counters: Arc<Counters>,
+}
+
+impl ConnectionCounts {
+    pub fn new(max: usize, per_ip_max: Option<usize>) -> Self {
+        ConnectionCounts {
+            max,
+            per_ip_max,
+            slots: Arc::new(Semaphore::new(max)),
+            counters: Arc::default(),
+        }
+    }
+    pub fn active(&self) -> usize {
+        self.max - self.slots.available_permits()
+    }
+    pub fn active_from(&self, ip: IpAddr) -> usize {
+        let per_ip = self
+            .counters
+            .per_ip
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        per_ip.get(&ip).copied().unwrap_or(0)
+    }
+    pub fn accepted(&self) -> u64 {
+        self.counters.accepted.load(Ordering::Relaxed)
+    }
+    pub fn rejected(&self) -> u64 {
+        self.counters.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Waits until there is room for another connection.
+    pub(crate) async fn reserve(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.slots).acquire_owned().await.ok()
+    }
+
+    /// A slot for a connection from `ip`, using `reserved` if there is one.
+    /// `None` when either limit is reached. Connections without an address
+    /// only count against the overall limit.
+    pub(crate) fn admit(
+        &self,
+        ip: Option<IpAddr>,
+        reserved: Option<OwnedSemaphorePermit>,
+    ) -> Option<ConnectionSlot> {
+        let admitted = reserved
+            .or_else(|| Arc::clone(&self.slots).try_acquire_owned().ok())
+            .and_then(|permit| {
+                let Some(ip) = ip else {
+                    return Some(ConnectionSlot {
+                        _permit: permit,
+                        ip: None,
+                        counters: Arc::clone(&self.counters),
+                    });
+                };
+                let mut per_ip = self
+                    .counters
+                    .per_ip
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner());
+                let count = per_ip.entry(ip).or_insert(0);
+                if self.per_ip_max.is_some_and(|max| *count >= max) {
+                    if *count == 0 {
+                        per_ip.remove(&ip);
+                    }
+                    return None;
+                }
+                *count += 1;
+                Some(ConnectionSlot {
+                    _permit: permit,
+                    ip: Some(ip),
+                    counters: Arc::clone(&self.counters),
+                })
+            });
+        let counter = match admitted {
+            Some(_) => &self.counters.accepted,
+            None => &self.counters.rejected,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        admitted
+    }
+}
+
+/// Held for the life of a connection, gives its place back when dropped.
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    _permit: OwnedSemaphorePermit,
+    ip: Option<IpAddr>,
+    counters: Arc<Counters>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let Some(ip) = self.ip else { return };
+        let mut per_ip = self
+            .counters
+            .per_ip
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+// How long a rejected connection is kept around to get its answer out.
+const REJECT_LINGER: Duration = Duration::from_secs(1);
+// Most rejected connections lingering at once; past it they are just closed.
+const MAX_LINGERING: usize = 256;
+
+/// Answers a connection there is no room for. Closing with the request still
+/// unread would reset the connection and could destroy the answer, so what
+/// the client sends is read and dropped until it closes, for a short while.
+/// That happens in `lingering`, which is kept to a bound.
+pub(crate) fn reject<S>(lingering: &mut JoinSet<()>, mut stream: S, retry_after: Duration)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if lingering.len() >= MAX_LINGERING {
+        return;
+    }
+    let mut resp = mk_response("", StatusCode::SC503).into_inner();
+    resp.set_header(Header::retry_after(retry_after));
+    resp.set_header(Header::connection(Connection::Close));
+    let bytes: Vec<u8> = resp.into();
+    let answer = async move {
+        stream.write_all(&bytes).await?;
+        stream.shutdown().await?;
+        let mut discard = [0; 1024];
+        while stream.read(&mut discard).await? > 0 {}
+        Ok::<_, std::io::Error>(())
+    };
+    lingering.spawn(async move {
+        let _ = tokio::time::timeout(REJECT_LINGER, answer).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_admit() {
+        let counts = ConnectionCounts::new(3, Some(2));
+        let (a, b) = (
+            IpAddr::from(Ipv4Addr::LOCALHOST),
+            IpAddr::from([10, 0, 0, 1]),
+        );
+        let first = counts.admit(Some(a), None);
+        let second = counts.admit(Some(a), None);
+        assert!(first.is_some() && second.is_some());
+        // Per address first, then overall.
+        assert!(counts.admit(Some(a), None).is_none());
+        let third = counts.admit(Some(b), None);
+        assert!(third.is_some());
+        assert!(counts.admit(Some(b), None).is_none());
+        assert_eq!((counts.active(), counts.active_from(a)), (3, 2));
+        drop(first);
+        assert_eq!((counts.active(), counts.active_from(a)), (2, 1));
+        assert_eq!((counts.accepted(), counts.rejected()), (3, 2));
+        // No address, no per address limit.
+        let unix = counts.admit(None, None);
+        assert!(unix.is_some());
+        assert_eq!((counts.active(), counts.active_from(a)), (3, 1));
+    }
+}
@@ -1,15 +1,23 @@
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::future::Future;
 use std::io::Write;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
 
 use bytes::Bytes;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 
 use crate::{
-    AcceptEncoding, Connection, ContentType, Context, Encoding, Error, Header, Headers, HttpMethod,
-    Request, RequestBody, Response, ResponseBody, Result, StatusCode, StatusLine, UserAgent,
+    evaluate_preconditions, safe_join, AcceptEncoding, BodyReader, Connection, ConnectionInfo,
+    ContentType, Context, DirEntry, Encoding, Error, FileMeta, FileOps, Header, Headers,
+    HttpMethod, Interim, PeerCredentials, PendingWrite, Precondition, Request, RequestBody,
+    Response, ResponseBody, Result, StatusCode, StatusLine, TlsInfo, UpgradeHandler, UserAgent,
+    Validators, WriteOptions,
 };
 
 #[derive(Debug, Clone)]
@@ -20,10 +28,10 @@ pub enum Body<'a> {
 }
 
 impl<'a> Length for Body<'a> {
-    fn len(&self) -> u32 {
+    fn len(&self) -> u64 {
         match self {
-            Body::Text(v) => v.len() as u32,
-            Body::Bin(v) => v.len() as u32,
+            Body::Text(v) => v.len() as u64,
+            Body::Bin(v) => v.len() as u64,
             Body::Empty => 0,
         }
     }
@@ -32,25 +40,25 @@ impl<'a> Length for Body<'a> {
 pub struct UnitT;
 
 trait Length {
-    fn len(&self) -> u32;
+    fn len(&self) -> u64;
 }
 
 pub trait AsBody {
-    fn body(&self) -> Body;
+    fn body(&self) -> Body<'_>;
 }
 
 impl AsBody for &str {
-    fn body(&self) -> Body {
-        if self.len() != 0 {
+    fn body(&self) -> Body<'_> {
+        if !self.is_empty() {
             Body::Text(self)
         } else {
             Body::Empty
         }
     }
 }
-impl<'a> AsBody for String {
-    fn body(&self) -> Body {
-        if self.len() != 0 {
+impl AsBody for String {
+    fn body(&self) -> Body<'_> {
+        if !self.is_empty() {
             Body::Text(self.as_str())
         } else {
             Body::Empty
@@ -58,8 +66,8 @@ impl<'a> AsBody for String {
     }
 }
 impl AsBody for Bytes {
-    fn body(&self) -> Body {
-        if self.len() != 0 {
+    fn body(&self) -> Body<'_> {
+        if !self.is_empty() {
             Body::Bin(self.clone())
         } else {
             Body::Empty
@@ -67,7 +75,7 @@ impl AsBody for Bytes {
     }
 }
 impl Body<'_> {
-    fn to_bin(self) -> Bytes {
+    fn into_bin(self) -> Bytes {
         match self {
             Body::Text(str) => Bytes::from(str.as_bytes().to_vec()),
             Body::Bin(b) => b,
@@ -77,6 +85,46 @@ impl Body<'_> {
 }
 type RequestRef = Arc<Request>;
 type ResponseRef = RefCell<Response>;
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+type BodyHandlerFn =
+    dyn for<'a> Fn(BodyReader<'a>) -> BoxFuture<'a, Result<Response>> + Send + Sync;
+
+/// Produces the response once the request body has been consumed, for routes
+/// that cannot answer before seeing the body, or that must not hold it in
+/// memory. The server runs it with a reader over the connection.
+#[derive(Clone)]
+pub struct BodyHandler(Arc<BodyHandlerFn>);
+
+impl BodyHandler {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: for<'a> Fn(BodyReader<'a>) -> BoxFuture<'a, Result<Response>> + Send + Sync + 'static,
+    {
+        BodyHandler(Arc::new(f))
+    }
+    pub async fn handle(&self, body: BodyReader<'_>) -> Result<Response> {
+        (self.0)(body).await
+    }
+    /// Runs `f` on the response the handler makes.
+    pub fn map_response<F>(self, f: F) -> Self
+    where
+        F: Fn(Response) -> Result<Response> + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        BodyHandler::new(move |body| {
+            let (handler, f) = (self.clone(), Arc::clone(&f));
+            Box::pin(async move { f(handler.handle(body).await?) })
+        })
+    }
+}
+
+impl Debug for BodyHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BodyHandler")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct New();
 #[derive(Debug, Clone)]
@@ -84,10 +132,21 @@ pub struct Incomplete(RequestRef);
 #[derive(Debug, Clone)]
 pub struct Complete(pub RequestRef, pub ResponseRef);
 #[derive(Debug, Clone)]
+pub struct Deferred(pub RequestRef, pub BodyHandler);
+/// The response is sent, then the connection is the handler's if the
+/// response agreed to switch protocols.
+#[derive(Debug, Clone)]
+pub struct Upgrading(pub RequestRef, pub ResponseRef, pub UpgradeHandler);
+/// What a route answers to a request for another protocol.
+#[derive(Debug, Clone)]
+pub struct Switch(pub ResponseRef, pub UpgradeHandler);
+#[derive(Debug, Clone)]
 pub enum State {
     //New(New),
     Incomplete(Incomplete),
     Complete(Complete),
+    Deferred(Deferred),
+    Upgrading(Upgrading),
 }
 
 impl State {
@@ -97,15 +156,32 @@ impl State {
     pub fn complete(req: RequestRef, resp: ResponseRef) -> State {
         Self::Complete(Complete(Arc::clone(&req), RefCell::clone(&resp)))
     }
+    pub fn deferred(req: RequestRef, handler: BodyHandler) -> State {
+        Self::Deferred(Deferred(Arc::clone(&req), handler))
+    }
+    pub fn upgrading(req: RequestRef, Switch(resp, handler): Switch) -> State {
+        Self::Upgrading(Upgrading(Arc::clone(&req), resp, handler))
+    }
+    pub fn connection_info(&self) -> Option<ConnectionInfo> {
+        self.request().connection_info()
+    }
     pub fn request(&self) -> RequestRef {
         match self {
-            State::Incomplete(Incomplete(r)) => Arc::clone(&r),
-            State::Complete(Complete(r, _)) => Arc::clone(&r),
+            State::Incomplete(Incomplete(r)) => Arc::clone(r),
+            State::Complete(Complete(r, _)) => Arc::clone(r),
+            State::Deferred(Deferred(r, _)) => Arc::clone(r),
+            State::Upgrading(Upgrading(r, _, _)) => Arc::clone(r),
         }
     }
     fn set_response(&self, resp: ResponseRef) -> State {
         State::complete(self.request(), resp)
     }
+    fn set_body_handler(&self, handler: BodyHandler) -> State {
+        State::deferred(self.request(), handler)
+    }
+    fn set_switch(&self, switch: Switch) -> State {
+        State::upgrading(self.request(), switch)
+    }
 }
 
 pub trait Endpoint {
@@ -201,12 +277,41 @@ pub trait Endpoint {
     {
         SetResponse { h: self, g }
     }
+    fn set_body_handler<G>(self, g: G) -> SetBodyHandler<Self, G>
+    where
+        G: Endpoint<Output = BodyHandler>,
+        Self: Sized,
+    {
+        SetBodyHandler { h: self, g }
+    }
+    fn set_switch<G>(self, g: G) -> SetSwitch<Self, G>
+    where
+        G: Endpoint<Output = Switch>,
+        Self: Sized,
+    {
+        SetSwitch { h: self, g }
+    }
+    /// Also applies to the response a body handler makes, once it is made.
     fn modify_response<F>(self, f: F) -> ModifyResponse<Self, F>
     where
-        F: Fn(ResponseRef) -> Result<ResponseRef>,
+        F: Fn(ResponseRef) -> Result<ResponseRef> + Send + Sync + 'static,
         Self: Sized,
     {
-        ModifyResponse { h: self, f }
+        ModifyResponse {
+            h: self,
+            f: Arc::new(f),
+        }
+    }
+    /// Answers 413 instead when this endpoint matches a request that
+    /// announces a body over `limit` bytes. Requests it does not match fall
+    /// through to other routes whatever their size. What the endpoint made
+    /// is dropped: a body handler never sees the body, a response is never
+    /// sent. A body left unread ends the connection after the response.
+    fn max_body(self, limit: u64) -> MaxBody<Self>
+    where
+        Self: Sized,
+    {
+        MaxBody { h: self, limit }
     }
 }
 
@@ -457,16 +562,56 @@ where
     }
 }
 
+pub struct SetBodyHandler<H, G> {
+    h: H,
+    g: G,
+}
+
+impl<H, G, O1> Endpoint for SetBodyHandler<H, G>
+where
+    O1: Debug + Clone,
+    H: Endpoint<Output = O1>,
+    G: Endpoint<Output = BodyHandler>,
+{
+    type Output = UnitT;
+
+    fn handle(&self, r: State) -> Result<(State, Self::Output)> {
+        let (s, _) = self.h.handle(r)?;
+        let (ss, handler) = self.g.handle(s)?;
+        Ok((ss.set_body_handler(handler), UnitT))
+    }
+}
+
+pub struct SetSwitch<H, G> {
+    h: H,
+    g: G,
+}
+
+impl<H, G, O1> Endpoint for SetSwitch<H, G>
+where
+    O1: Debug + Clone,
+    H: Endpoint<Output = O1>,
+    G: Endpoint<Output = Switch>,
+{
+    type Output = UnitT;
+
+    fn handle(&self, r: State) -> Result<(State, Self::Output)> {
+        let (s, _) = self.h.handle(r)?;
+        let (ss, switch) = self.g.handle(s)?;
+        Ok((ss.set_switch(switch), UnitT))
+    }
+}
+
 pub struct ModifyResponse<H, F> {
     h: H,
-    f: F,
+    f: Arc<F>,
 }
 
 impl<H, O, F> Endpoint for ModifyResponse<H, F>
 where
     O: Debug + Clone,
     H: Endpoint<Output = O>,
-    F: Fn(ResponseRef) -> Result<ResponseRef>,
+    F: Fn(ResponseRef) -> Result<ResponseRef> + Send + Sync + 'static,
 {
     type Output = O;
 
@@ -475,11 +620,42 @@ where
         let ss = match s {
             State::Incomplete(Incomplete(req)) => State::incomplete(req),
             State::Complete(Complete(req, res)) => State::complete(req, (self.f)(res)?),
+            // There is no response to modify until the handler has run.
+            State::Deferred(Deferred(req, handler)) => {
+                let f = Arc::clone(&self.f);
+                let handler = handler
+                    .map_response(move |resp| f(RefCell::new(resp)).map(RefCell::into_inner));
+                State::deferred(req, handler)
+            }
+            // What goes with a switch is the protocol's business.
+            State::Upgrading(u) => State::Upgrading(u),
         };
         Ok((ss, o))
     }
 }
 
+pub struct MaxBody<H> {
+    h: H,
+    limit: u64,
+}
+
+impl<H> Endpoint for MaxBody<H>
+where
+    H: Endpoint,
+{
+    type Output = H::Output;
+
+    fn handle(&self, r: State) -> Result<(State, Self::Output)> {
+        let (s, o) = self.h.handle(r)?;
+        let len = s.request().headers.content_length().map_or(0, |l| *l);
+        if len > self.limit {
+            Ok((s.set_response(mk_response("", StatusCode::SC413)), o))
+        } else {
+            Ok((s, o))
+        }
+    }
+}
+
 pub struct S {}
 
 impl Endpoint for S {
@@ -499,6 +675,24 @@ pub fn request() -> impl Endpoint<Output = RequestRef> {
 pub fn req_body() -> impl Endpoint<Output = Option<RequestBody>> {
     request().map(|v| v.body.clone())
 }
+pub fn connection_info() -> impl Endpoint<Output = Option<ConnectionInfo>> {
+    state().map(|v| v.connection_info())
+}
+pub fn remote_addr() -> impl Endpoint<Output = Option<SocketAddr>> {
+    connection_info().map(|v| v.and_then(|info| info.remote_addr))
+}
+/// What was negotiated, on TLS connections.
+pub fn tls_info() -> impl Endpoint<Output = Option<TlsInfo>> {
+    connection_info().map(|v| v.and_then(|info| info.tls))
+}
+/// The verified client certificate in DER, if the client showed one.
+pub fn peer_certificate() -> impl Endpoint<Output = Option<Vec<u8>>> {
+    tls_info().map(|v| v.and_then(|tls| tls.peer_certificates.into_iter().next()))
+}
+/// Who connected over a Unix socket; `None` on TCP.
+pub fn peer_credentials() -> impl Endpoint<Output = Option<PeerCredentials>> {
+    connection_info().map(|v| v.and_then(|info| info.peer_credentials))
+}
 pub fn user_agent() -> impl Endpoint<Output = Option<UserAgent>> {
     state().map(|v| v.request().user_agent())
 }
@@ -516,20 +710,30 @@ pub fn req_headers() -> impl Endpoint<Output = Headers> {
 }
 
 pub fn route_for(path: &str) -> impl Endpoint<Output = String> + use<'_> {
-    route().stop_if(move |v| path.to_string() != v)
+    route().stop_if(move |v| path != v)
 }
 pub fn modify_response<F>(f: F) -> impl Endpoint<Output = UnitT>
 where
-    F: Fn(ResponseRef) -> Result<ResponseRef> + 'static,
+    F: Fn(ResponseRef) -> Result<ResponseRef> + Send + Sync + 'static,
 {
-    state().modify_response(move |r| f(r)).unit()
+    state().modify_response(f).unit()
 }
+/// Also matches HEAD; the server drops the body when writing the response.
 pub fn get() -> impl Endpoint<Output = HttpMethod> {
-    http_method().stop_if(|v| !HttpMethod::is_get(&v))
+    http_method().stop_if(|v| !HttpMethod::is_get_or_head(&v))
 }
 pub fn post() -> impl Endpoint<Output = HttpMethod> {
     http_method().stop_if(|v| !HttpMethod::is_post(&v))
 }
+pub fn put() -> impl Endpoint<Output = HttpMethod> {
+    http_method().stop_if(|v| !HttpMethod::is_put(&v))
+}
+pub fn delete() -> impl Endpoint<Output = HttpMethod> {
+    http_method().stop_if(|v| !HttpMethod::is_delete(&v))
+}
+pub fn connect() -> impl Endpoint<Output = HttpMethod> {
+    http_method().stop_if(|v| !HttpMethod::is_connect(&v))
+}
 pub fn ok<'a, T>(body: T) -> impl Endpoint<Output = ResponseRef> + use<'a, T>
 where
     T: AsBody,
@@ -542,7 +746,7 @@ where
 {
     lift(mk_response(body, StatusCode::SC404))
 }
-pub fn mk_response<'a, H>(body: H, code: StatusCode) -> ResponseRef
+pub fn mk_response<H>(body: H, code: StatusCode) -> ResponseRef
 where
     H: AsBody,
 {
@@ -553,22 +757,328 @@ where
     };
     // let b = body.to_bin();
     let sl = match code {
+        StatusCode::SC100 => StatusLine::continue_(),
+        StatusCode::SC101 => StatusLine::switching_protocols(),
+        StatusCode::SC103 => StatusLine::early_hints(),
         StatusCode::SC200 => StatusLine::ok(),
         StatusCode::SC201 => StatusLine::created(),
+        StatusCode::SC204 => StatusLine::no_content(),
+        StatusCode::SC304 => StatusLine::not_modified(),
+        StatusCode::SC400 => StatusLine::bad_request(),
+        StatusCode::SC403 => StatusLine::forbidden(),
         StatusCode::SC404 => StatusLine::not_found(),
+        StatusCode::SC408 => StatusLine::request_timeout(),
+        StatusCode::SC409 => StatusLine::conflict(),
+        StatusCode::SC411 => StatusLine::length_required(),
+        StatusCode::SC412 => StatusLine::precondition_failed(),
+        StatusCode::SC413 => StatusLine::content_too_large(),
+        StatusCode::SC414 => StatusLine::uri_too_long(),
+        StatusCode::SC417 => StatusLine::expectation_failed(),
+        StatusCode::SC426 => StatusLine::upgrade_required(),
+        StatusCode::SC431 => StatusLine::request_header_fields_too_large(),
+        StatusCode::SC500 => StatusLine::internal_server_error(),
+        StatusCode::SC501 => StatusLine::not_implemented(),
+        StatusCode::SC502 => StatusLine::bad_gateway(),
+        StatusCode::SC503 => StatusLine::service_unavailable(),
+        StatusCode::SC504 => StatusLine::gateway_timeout(),
     };
+    // 204 and 304 never have content, so they do not describe one either.
+    if matches!(code, StatusCode::SC204 | StatusCode::SC304) {
+        return RefCell::new(Response(sl, vec![], None, vec![]));
+    }
     let len = body.len();
     let body = match body {
-        Body::Text(_) | Body::Bin(_) => Some(ResponseBody(body.to_bin())),
+        Body::Text(_) | Body::Bin(_) => Some(ResponseBody::Full(body.into_bin())),
         Body::Empty => None,
     };
     RefCell::new(Response(
         sl,
         vec![Header::content_type(ct), Header::content_length(len)],
         body,
+        vec![],
     ))
 }
 
+fn with_validators(resp: ResponseRef, validators: &Validators) -> ResponseRef {
+    if let Some(etag) = &validators.etag {
+        resp.borrow_mut().add_header(Header::etag(etag.clone()));
+    }
+    if let Some(date) = validators.last_modified {
+        resp.borrow_mut().add_header(Header::last_modified(date));
+    }
+    resp
+}
+fn json_escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '"' => "\\\"".to_string(),
+            '\\' => "\\\\".to_string(),
+            c if (c as u32) < 0x20 => format!("\\u{:04x}", c as u32),
+            c => c.to_string(),
+        })
+        .collect()
+}
+fn html_escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+fn entry_kind(entry: &DirEntry) -> &'static str {
+    if entry.meta.is_dir {
+        "directory"
+    } else {
+        "file"
+    }
+}
+fn listing_json(entries: &[DirEntry]) -> String {
+    let items: Vec<String> = entries
+        .iter()
+        .map(|e| {
+            format!(
+                r#"{{"name":"{}","size":{},"modified":"{}","type":"{}"}}"#,
+                json_escape(&e.name),
+                e.meta.len,
+                e.meta.last_modified(),
+                entry_kind(e)
+            )
+        })
+        .collect();
+    format!("[{}]", items.join(","))
+}
+fn listing_html(title: &str, entries: &[DirEntry]) -> String {
+    let rows: String = entries
+        .iter()
+        .map(|e| {
+            let name = html_escape(&e.name) + if e.meta.is_dir { "/" } else { "" };
+            format!(
+                r#"<tr><td><a href="{0}">{0}</a></td><td>{1}</td><td>{2}</td><td>{3}</td></tr>"#,
+                name,
+                e.meta.len,
+                e.meta.last_modified(),
+                entry_kind(e)
+            )
+        })
+        .collect();
+    let title = html_escape(title);
+    format!(
+        "<!DOCTYPE html><html><head><title>Index of {0}</title></head><body><h1>Index of {0}</h1>\
+         <table><tr><th>Name</th><th>Size</th><th>Modified</th><th>Type</th></tr>{1}</table></body></html>",
+        title, rows
+    )
+}
+/// Lists a directory as JSON or HTML, whichever `Accept` prefers, JSON
+/// being the default.
+fn listing_response(path: &str, req: &Request) -> ResponseRef {
+    let entries = match path.list() {
+        Ok(entries) => entries,
+        Err(_) => return mk_response("", StatusCode::SC404),
+    };
+    let html = req
+        .headers()
+        .accept()
+        .and_then(|a| a.prefers(&["application/json", "text/html"]))
+        == Some("text/html");
+    let (body, content_type) = if html {
+        (listing_html(&req.target(), &entries), ContentType::TextHtml)
+    } else {
+        (listing_json(&entries), ContentType::Json)
+    };
+    let resp = mk_response(body, StatusCode::SC200);
+    resp.borrow_mut()
+        .set_header(Header::content_type(content_type));
+    resp
+}
+fn file_response(path: &str, req: &Request) -> ResponseRef {
+    if path.ends_with('/') {
+        return listing_response(path, req);
+    }
+    let (body, validators) = match path.open() {
+        Ok((body, meta)) if !meta.is_dir => (body, meta.validators()),
+        _ => return mk_response("", StatusCode::SC404),
+    };
+    match evaluate_preconditions(req.http_method(), req.headers(), &validators) {
+        // 304 carries the validators a 200 would have had.
+        Precondition::NotModified => {
+            with_validators(mk_response("", StatusCode::SC304), &validators)
+        }
+        Precondition::Failed => mk_response("", StatusCode::SC412),
+        Precondition::Proceed => with_validators(RefCell::new(Response::file(body)), &validators),
+    }
+}
+/// Serves `file` from below `root`, answering conditional requests with 304.
+/// A path ending in `/` lists the directory instead. Byte ranges are not
+/// served, so a `Range` request gets the full 200 whether or not its
+/// `If-Range` holds (see `if_range_applies`), as RFC 9110 allows.
+pub fn serve_file(root: &str, file: &str) -> impl Endpoint<Output = ResponseRef> {
+    let path = safe_join(root, file);
+    request().map(move |req| match &path {
+        Some(path) => file_response(path, &req),
+        None => mk_response("", StatusCode::SC400),
+    })
+}
+
+// Checking the preconditions and writing has to happen as one step, or two
+// writers holding the same ETag could both pass `If-Match`. Each file has its
+// own lock, kept only while someone holds it.
+static FILE_LOCKS: Mutex<BTreeMap<PathBuf, Weak<Mutex<()>>>> = Mutex::new(BTreeMap::new());
+
+// Keyed by the canonical path, so one file has one lock whatever it is
+// called. The file itself may not exist yet, its directory usually does.
+fn file_lock(path: &str) -> Arc<Mutex<()>> {
+    let path = Path::new(path);
+    let key = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => dir.canonicalize().map(|dir| dir.join(name)),
+        _ => path.canonicalize(),
+    }
+    .unwrap_or_else(|_| path.to_path_buf());
+    let mut locks = FILE_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(lock) = locks.get(&key).and_then(Weak::upgrade) {
+        return lock;
+    }
+    locks.retain(|_, lock| lock.strong_count() > 0);
+    let lock = Arc::new(Mutex::new(()));
+    locks.insert(key, Arc::downgrade(&lock));
+    lock
+}
+
+// Answers an upload that must not go ahead, before anything was written.
+fn reject_upload(path: &str, req: &Request, current: Option<FileMeta>) -> Option<ResponseRef> {
+    if path.ends_with('/') || current.is_some_and(|meta| meta.is_dir) {
+        return Some(mk_response("", StatusCode::SC409));
+    }
+    let validators = current.map(|meta| meta.validators()).unwrap_or_default();
+    match evaluate_preconditions(req.http_method(), req.headers(), &validators) {
+        Precondition::Failed => Some(mk_response("", StatusCode::SC412)),
+        Precondition::Proceed | Precondition::NotModified => None,
+    }
+}
+// Preconditions are checked again right before the rename, as the target may
+// have changed while the body was streaming in.
+fn commit_upload(path: &str, req: &Request, pending: PendingWrite) -> ResponseRef {
+    let lock = file_lock(path);
+    let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+    let current = path.metadata().ok();
+    if let Some(rejected) = reject_upload(path, req, current) {
+        return rejected;
+    }
+    let code = if current.is_some() {
+        StatusCode::SC204
+    } else {
+        StatusCode::SC201
+    };
+    match pending.commit().and_then(|_| path.metadata()) {
+        Ok(meta) => with_validators(mk_response("", code), &meta.validators()),
+        Err(_) => mk_response("", StatusCode::SC500),
+    }
+}
+// The body is handed to a blocking task chunk by chunk through a small
+// channel, so neither side ever holds more than a few chunks.
+async fn upload(
+    path: Option<String>,
+    req: RequestRef,
+    mut body: BodyReader<'_>,
+) -> Result<Response> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(mk_response("", StatusCode::SC400).into_inner()),
+    };
+    if let Some(rejected) = reject_upload(&path, &req, path.as_str().metadata().ok()) {
+        return Ok(rejected.into_inner());
+    }
+    let expected = body.remaining();
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Bytes>(4);
+    let writer = tokio::task::spawn_blocking(move || {
+        let mut pending = match path
+            .as_str()
+            .begin_write(WriteOptions::durable().create_dirs())
+        {
+            Ok(pending) => pending,
+            // A file where a directory should be.
+            Err(_) if Path::new(&path).ancestors().skip(1).any(Path::is_file) => {
+                return Ok(mk_response("", StatusCode::SC409).into_inner())
+            }
+            Err(e) => return Err(e),
+        };
+        let mut written = 0;
+        while let Some(chunk) = rx.blocking_recv() {
+            pending.write(&chunk)?;
+            written += chunk.len() as u64;
+        }
+        // Short of the announced length the upload was cut off; dropping
+        // `pending` throws the partial file away.
+        if written != expected {
+            return Err(Error::GeneralError("Incomplete upload".to_string()));
+        }
+        Ok(commit_upload(&path, &req, pending).into_inner())
+    });
+    while let Some(chunk) = body.chunk().await? {
+        if tx.send(chunk).await.is_err() {
+            break;
+        }
+    }
+    drop(tx);
+    writer.await.context("Upload task")?
+}
+// Blocks on the file's lock and the file system; run it off the runtime.
+fn delete_response(path: &str, req: &Request) -> ResponseRef {
+    let lock = file_lock(path);
+    let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+    let meta = match path.metadata() {
+        Ok(meta) => meta,
+        Err(_) => return mk_response("", StatusCode::SC404),
+    };
+    // Directories go only when addressed as one, and only when empty.
+    if meta.is_dir != path.ends_with('/') {
+        return mk_response("", StatusCode::SC409);
+    }
+    match evaluate_preconditions(req.http_method(), req.headers(), &meta.validators()) {
+        Precondition::Failed => mk_response("", StatusCode::SC412),
+        Precondition::Proceed | Precondition::NotModified => match path.remove() {
+            Ok(_) => mk_response("", StatusCode::SC204),
+            Err(_) if meta.is_dir => mk_response("", StatusCode::SC409),
+            Err(_) => mk_response("", StatusCode::SC500),
+        },
+    }
+}
+/// Deletes `file` below `root`, or an empty directory when the path ends in
+/// `/`. Write preconditions apply as for uploads. A request body is not read.
+pub fn delete_file(root: &str, file: &str) -> impl Endpoint<Output = BodyHandler> {
+    let path = safe_join(root, file);
+    request().map(move |req| {
+        let path = path.clone();
+        BodyHandler::new(move |_| {
+            let (path, req) = (path.clone(), Arc::clone(&req));
+            Box::pin(async move {
+                let path = match path {
+                    Some(path) => path,
+                    None => return Ok(mk_response("", StatusCode::SC400).into_inner()),
+                };
+                let deleting =
+                    tokio::task::spawn_blocking(move || delete_response(&path, &req).into_inner());
+                deleting.await.context("Delete task")
+            })
+        })
+    })
+}
+/// Streams the request body to `file` below `root`, creating missing
+/// directories. Answers 201 for a new file and 204 for a replacement, honours
+/// `If-Match`, `If-None-Match` and `If-Unmodified-Since`, and carries the new
+/// `ETag`.
+pub fn store_file(root: &str, file: &str) -> impl Endpoint<Output = BodyHandler> {
+    let path = safe_join(root, file);
+    request().map(move |req| {
+        let path = path.clone();
+        BodyHandler::new(move |body| Box::pin(upload(path.clone(), Arc::clone(&req), body)))
+    })
+}
+
 struct Lift<T> {
     t: T,
 }
@@ -598,10 +1108,20 @@ fn gzip_header() -> impl Endpoint<Output = Option<Encoding>> {
 pub fn gzip() -> impl Endpoint<Output = Option<UnitT>> {
     gzip_header().flat_map_op(|_| {
         modify_response(|r| {
+            // A stream is sent as it is made, there is nothing to compress
+            // yet; a file goes out from disk rather than read into memory.
+            let as_is = matches!(
+                r.borrow().2,
+                Some(ResponseBody::Stream(_) | ResponseBody::File(_))
+            );
+            if as_is {
+                return Ok(r);
+            }
             r.borrow_mut()
                 .add_header(Header::content_encoding(Encoding::Gzip));
+            r.borrow_mut().weaken_etag();
             r.borrow_mut()
-                .set_body(|rb| Ok(ResponseBody(gzip_encode(rb.clone().0)?)))?;
+                .set_body(|rb| Ok(ResponseBody::Full(gzip_encode(rb.bytes()?)?)))?;
             Ok(r)
         })
     })
@@ -619,6 +1139,22 @@ pub fn close_connection() -> impl Endpoint<Output = Option<UnitT>> {
         })
     })
 }
+/// Sends `status_line` and `headers` ahead of the response set before, to
+/// clients that take interim responses. A body handler's response is made
+/// only after the body is in, and so is anything ahead of it.
+pub fn interim(status_line: StatusLine, headers: Vec<Header>) -> impl Endpoint<Output = UnitT> {
+    modify_response(move |r| {
+        r.borrow_mut()
+            .add_interim(Interim(status_line, headers.clone()));
+        Ok(r)
+    })
+}
+/// 103 Early Hints with a `Link` for each of `links`, for the client to
+/// preload while it waits.
+pub fn early_hints(links: &[&str]) -> impl Endpoint<Output = UnitT> {
+    let links = links.iter().map(|link| Header::link(link)).collect();
+    interim(StatusLine::early_hints(), links)
+}
 fn gzip_encode(b: Bytes) -> Result<Bytes> {
     let mut enc = GzEncoder::new(Vec::new(), Compression::default());
     enc.write_all(&b).with_context(|| "")?;
@@ -635,10 +1171,17 @@ pub mod route {
     pub fn post(path: &str) -> impl Endpoint<Output = UnitT> + use<'_> {
         super::post().and(route_for(path)).unit()
     }
+    pub fn put(path: &str) -> impl Endpoint<Output = UnitT> + use<'_> {
+        super::put().and(route_for(path)).unit()
+    }
+    pub fn delete(path: &str) -> impl Endpoint<Output = UnitT> + use<'_> {
+        super::delete().and(route_for(path)).unit()
+    }
 }
 #[cfg(test)]
 mod test {
     use crate::parse_request;
+    use bytes::BytesMut;
 
     use super::*;
 
@@ -675,8 +1218,189 @@ mod test {
         let state = State::incomplete(Arc::new(req));
 
         let (_, _) = route::get("/echo")
-            .set_response(path().flat_map(|v| ok(v)))
+            .set_response(path().flat_map(ok))
             .handle(state)?;
         Ok(())
     }
+
+    // A directory below the system's temporary one for a single test,
+    // removed with everything in it when dropped.
+    struct TempRoot(String);
+
+    impl TempRoot {
+        fn new(name: &str) -> Self {
+            static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+            let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let dir = std::env::temp_dir().join(format!("{name}-{}-{n}", std::process::id()));
+            TempRoot(dir.to_string_lossy().to_string())
+        }
+    }
+
+    impl Drop for TempRoot {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Uploads, downloads and deletes below `root`, routed as in main.rs.
+    fn files(root: &str) -> impl Endpoint {
+        let (store, delete, serve) = (root.to_string(), root.to_string(), root.to_string());
+        route::put("/files")
+            .set_body_handler(path().flat_map(move |f| store_file(&store, &f)))
+            .or(route::delete("/files")
+                .set_body_handler(path().flat_map(move |f| delete_file(&delete, &f))))
+            .or(route::get("/files").set_response(path().flat_map(move |f| serve_file(&serve, &f))))
+    }
+
+    // Answers `head` with `files`, a body handler reading `body` for as long
+    // as the head says it is.
+    async fn run(root: &str, head: &[u8], body: &[u8]) -> Result<Response> {
+        let req = Arc::new(parse_request(head)?);
+        match files(root).handle(State::incomplete(req))?.0 {
+            State::Complete(Complete(_, resp)) => Ok(resp.into_inner()),
+            State::Deferred(Deferred(req, handler)) => {
+                let declared = req.headers.content_length().map(|l| *l);
+                let mut remaining = declared.unwrap_or(body.len() as u64);
+                let (mut buffered, mut reader) = (BytesMut::new(), body);
+                let body = BodyReader::new(&mut reader, &mut buffered, &mut remaining);
+                handler.handle(body).await
+            }
+            State::Incomplete(_) | State::Upgrading(_) => Err(Error::CantHandle),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_nested_file() -> Result<()> {
+        let root = TempRoot::new("store-nested");
+        let status = |resp: Response| resp.0.status_code();
+        let large = vec![b'x'; 200 * 1024];
+        let put = format!(
+            "PUT /files/a/b/c.txt HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            large.len()
+        );
+        let put = put.as_bytes();
+        assert_eq!(status(run(&root.0, put, &large).await?), StatusCode::SC201);
+        assert_eq!(status(run(&root.0, put, &large).await?), StatusCode::SC204);
+        let get = b"GET /files/a/b/c.txt HTTP/1.1\r\n\r\n";
+        assert_eq!(status(run(&root.0, get, b"").await?), StatusCode::SC200);
+        let stored = format!("{}/a/b/c.txt", root.0).as_str().read()?;
+        assert_eq!(stored.len(), large.len());
+        // Cut short, the upload leaves nothing behind.
+        let short = b"PUT /files/d.txt HTTP/1.1\r\nContent-Length: 10\r\n\r\n";
+        assert!(run(&root.0, short, b"abc").await.is_err());
+        assert!(!std::path::Path::new(&format!("{}/d.txt", root.0)).exists());
+        let escape = b"PUT /files/a/../../x HTTP/1.1\r\n\r\n";
+        assert_eq!(status(run(&root.0, escape, b"").await?), StatusCode::SC400);
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_lock() {
+        let dir = std::env::temp_dir();
+        let lock = file_lock(&format!("{}/lock-a", dir.display()));
+        let same = file_lock(&format!("{}/./lock-a", dir.display()));
+        let other = file_lock(&format!("{}/lock-b", dir.display()));
+        assert!(Arc::ptr_eq(&lock, &same));
+        assert!(!Arc::ptr_eq(&lock, &other));
+    }
+
+    #[tokio::test]
+    async fn test_list_and_delete() -> Result<()> {
+        let root = TempRoot::new("list-delete");
+        format!("{}/dir/a.txt", root.0)
+            .as_str()
+            .create_and_write(Bytes::from("a"))?;
+        let json = b"GET /files/dir/ HTTP/1.1\r\nAccept: application/json\r\n\r\n";
+        let listing = run(&root.0, json, b"").await?;
+        let body = listing.2.and_then(|b| b.bytes().ok()).unwrap_or_default();
+        assert!(body.starts_with(br#"[{"name":"a.txt","size":1,"modified":""#));
+        let html = b"GET /files/ HTTP/1.1\r\nAccept: text/html\r\n\r\n";
+        let listing = run(&root.0, html, b"").await?;
+        assert!(listing
+            .1
+            .contains(&Header::content_type(ContentType::TextHtml)));
+
+        let delete = |req: &'static [u8]| {
+            let root = root.0.clone();
+            async move { run(&root, req, b"").await.map(|r| r.0.status_code()) }
+        };
+        let dir = b"DELETE /files/dir/ HTTP/1.1\r\n\r\n";
+        assert_eq!(delete(dir).await?, StatusCode::SC409);
+        let file = b"DELETE /files/dir/a.txt HTTP/1.1\r\n\r\n";
+        assert_eq!(delete(file).await?, StatusCode::SC204);
+        assert_eq!(delete(dir).await?, StatusCode::SC204);
+        assert_eq!(delete(dir).await?, StatusCode::SC404);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gzip_leaves_files_on_disk() -> Result<()> {
+        let root = TempRoot::new("gzip-file");
+        format!("{}/a.txt", root.0)
+            .as_str()
+            .create_and_write(Bytes::from("a".repeat(1024)))?;
+        let files = files(&root.0).and(gzip());
+        let req = b"GET /files/a.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n";
+        let state = State::incomplete(Arc::new(parse_request(req)?));
+        let resp = match files.handle(state)?.0 {
+            State::Complete(Complete(_, resp)) => resp.into_inner(),
+            _ => return Err(Error::CantHandle),
+        };
+        assert!(matches!(resp.2, Some(ResponseBody::File(_))));
+        assert!(!resp.1.contains(&Header::content_encoding(Encoding::Gzip)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_modify_deferred_response() -> Result<()> {
+        let handler = BodyHandler::new(|_| {
+            Box::pin(async { Ok(mk_response("stored", StatusCode::SC201).into_inner()) })
+        });
+        let upload = route::put("/files")
+            .set_body_handler(lift(handler))
+            .and(gzip().and(close_connection()));
+        let req = b"PUT /files/a HTTP/1.1\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n";
+        let state = State::incomplete(Arc::new(parse_request(req)?));
+        let handler = match upload.handle(state)?.0 {
+            State::Deferred(Deferred(_, handler)) => handler,
+            _ => return Err(Error::CantHandle),
+        };
+        let (mut remaining, mut buffered) = (0, BytesMut::new());
+        let mut empty = tokio::io::empty();
+        let body = BodyReader::new(&mut empty, &mut buffered, &mut remaining);
+        let resp = handler.handle(body).await?;
+        assert_eq!(resp.0.status_code(), StatusCode::SC201);
+        assert!(resp.1.contains(&Header::connection(Connection::Close)));
+        assert!(resp.1.contains(&Header::content_encoding(Encoding::Gzip)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_body() -> Result<()> {
+        let store = BodyHandler::new(|_| {
+            Box::pin(async { Ok(mk_response("stored", StatusCode::SC201).into_inner()) })
+        });
+        let upload = route::put("/files")
+            .set_body_handler(lift(store))
+            .max_body(4)
+            .or(route::post("/files").set_response(ok("posted")));
+        let run = |req: &[u8]| -> Result<StatusCode> {
+            let state = State::incomplete(Arc::new(parse_request(req)?));
+            match upload.handle(state)?.0 {
+                State::Complete(Complete(_, resp)) => Ok(resp.borrow().0.status_code()),
+                // The handler would read the body; it was let through.
+                State::Deferred(_) => Ok(StatusCode::SC201),
+                _ => Err(Error::CantHandle),
+            }
+        };
+        let small = b"PUT /files/a HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcd";
+        let large = b"PUT /files/a HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde";
+        assert_eq!(run(small)?, StatusCode::SC201);
+        assert_eq!(run(large)?, StatusCode::SC413);
+        // Over the limit, other routes are not affected.
+        let post = b"POST /files/a HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde";
+        assert_eq!(run(post)?, StatusCode::SC200);
+        assert!(run(b"GET /files/a HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde").is_err());
+        Ok(())
+    }
 }
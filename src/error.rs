@@ -1,4 +1,5 @@
 use crate::Error::ErrorWrapper;
+use crate::StatusCode;
 use std::fmt::Display;
 use std::num::ParseIntError;
 use std::str::Utf8Error;
@@ -22,6 +23,9 @@ pub enum Error {
     ParseInt(#[from] ParseIntError),
     #[error("Cant handle request")]
     CantHandle,
+    /// The request is answered with this status without being routed.
+    #[error("Request rejected with {0:?}")]
+    Rejected(StatusCode),
 }
 
 pub trait Context<T, E> {
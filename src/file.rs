@@ -1,25 +1,153 @@
-use crate::{Context, Result};
+use crate::{Context, ETag, HttpDate, Result, Validators};
 use bytes::Bytes;
-use std::fs::{create_dir_all, File as F};
+use std::fs::{create_dir_all, remove_file, rename, File as F, OpenOptions};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileMeta {
+    pub len: u64,
+    pub modified: SystemTime,
+    pub is_dir: bool,
+}
+
+impl FileMeta {
+    /// Strong unless the filesystem only keeps whole-second timestamps and the
+    /// file was touched within the last second, where a second write could
+    /// land without changing the timestamp we hash.
+    pub fn etag(&self) -> ETag {
+        let since_epoch = self.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let tag = format!(
+            "{:x}.{:x}-{:x}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_nanos(),
+            self.len
+        );
+        let settled = since_epoch.subsec_nanos() != 0
+            || SystemTime::now()
+                .duration_since(self.modified)
+                .map(|age| age >= Duration::from_secs(1))
+                .unwrap_or(false);
+        if settled {
+            ETag::strong(&tag)
+        } else {
+            ETag::weak(&tag)
+        }
+    }
+    pub fn last_modified(&self) -> HttpDate {
+        self.modified.into()
+    }
+    pub fn validators(&self) -> Validators {
+        Validators {
+            etag: Some(self.etag()),
+            last_modified: Some(self.last_modified()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WriteOptions {
+    /// Also fsync the parent directory, so the rename itself survives a crash.
+    pub sync_dir: bool,
+    /// Create missing parent directories first.
+    pub create_dirs: bool,
+}
+
+impl WriteOptions {
+    pub fn durable() -> Self {
+        WriteOptions {
+            sync_dir: true,
+            ..Default::default()
+        }
+    }
+    pub fn create_dirs(self) -> Self {
+        WriteOptions {
+            create_dirs: true,
+            ..self
+        }
+    }
+}
+
+/// An open file to be sent as a response body. The handle pins the content:
+/// a write renaming a new file into place does not change what is sent.
+#[derive(Debug, Clone)]
+pub struct FileBody {
+    file: Arc<F>,
+    len: u64,
+}
+
+impl FileBody {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn file(&self) -> &F {
+        &self.file
+    }
+    pub fn read(&self) -> Result<Bytes> {
+        let mut buffer = vec![0; self.len as usize];
+        self.file
+            .read_exact_at(&mut buffer, 0)
+            .context("Reading file")?;
+        Ok(Bytes::from(buffer))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub meta: FileMeta,
+}
+
+/// Writes never touch the target in place: data goes to a temporary file in
+/// the same directory, is fsynced and then renamed over the target, so readers
+/// see either the old content or the new one.
 pub trait FileOps {
     fn read(&self) -> Result<Bytes>;
+    fn list(&self) -> Result<Vec<DirEntry>>;
+    fn remove(&self) -> Result<()>;
     fn write(&self, data: Bytes) -> Result<()>;
+    fn write_with(&self, data: Bytes, options: WriteOptions) -> Result<()>;
+    fn begin_write(&self, options: WriteOptions) -> Result<PendingWrite>;
     fn create_and_write(&self, data: Bytes) -> Result<()>;
+    fn metadata(&self) -> Result<FileMeta>;
+    fn open(&self) -> Result<(FileBody, FileMeta)>;
 }
 
 impl FileOps for &str {
     fn read(&self) -> Result<Bytes> {
         read(self)
     }
+    fn list(&self) -> Result<Vec<DirEntry>> {
+        list(self)
+    }
+    fn remove(&self) -> Result<()> {
+        remove(self)
+    }
 
     fn write(&self, data: Bytes) -> Result<()> {
         write(self, data)
     }
+    fn write_with(&self, data: Bytes, options: WriteOptions) -> Result<()> {
+        write_with(self, data, options)
+    }
+    fn begin_write(&self, options: WriteOptions) -> Result<PendingWrite> {
+        begin_write(self, options)
+    }
     fn create_and_write(&self, data: Bytes) -> Result<()> {
-        create_and_write(&self, data)
+        create_and_write(self, data)
+    }
+    fn metadata(&self) -> Result<FileMeta> {
+        metadata(self)
+    }
+    fn open(&self) -> Result<(FileBody, FileMeta)> {
+        open(self)
     }
 }
 pub fn read(path: &str) -> Result<Bytes> {
@@ -29,14 +157,160 @@ pub fn read(path: &str) -> Result<Bytes> {
     Ok(Bytes::from(buffer))
 }
 
+/// Entries sorted by name. Dotfiles, including in-flight temporary files, are
+/// left out.
+pub fn list(path: &str) -> Result<Vec<DirEntry>> {
+    let mut entries = vec![];
+    for entry in std::fs::read_dir(path).context("List directory")? {
+        let entry = entry.context("Read directory entry")?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        if let Ok(meta) = metadata(&path.to_string_lossy()) {
+            entries.push(DirEntry { name, meta });
+        }
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Removes a file, or a directory as long as it is empty.
+pub fn remove(path: &str) -> Result<()> {
+    if metadata(path)?.is_dir {
+        std::fs::remove_dir(path).context("Remove directory")
+    } else {
+        remove_file(path).context("Remove file")
+    }
+}
+
+pub fn metadata(path: &str) -> Result<FileMeta> {
+    file_meta(std::fs::metadata(path).context("Read metadata")?)
+}
+
+/// Opens a file for sending. The metadata comes from the open handle, so it
+/// describes exactly the content that will be sent.
+pub fn open(path: &str) -> Result<(FileBody, FileMeta)> {
+    let file = F::open(path).context("Open file {path}")?;
+    let meta = file_meta(file.metadata().context("Read metadata")?)?;
+    let body = FileBody {
+        file: Arc::new(file),
+        len: meta.len,
+    };
+    Ok((body, meta))
+}
+
+fn file_meta(meta: std::fs::Metadata) -> Result<FileMeta> {
+    Ok(FileMeta {
+        len: meta.len(),
+        modified: meta.modified().context("Read modification time")?,
+        is_dir: meta.is_dir(),
+    })
+}
+
 pub fn write(path: &str, data: Bytes) -> Result<()> {
-    let mut file = F::create(path).with_context(|| "")?;
-    file.write_all(&data).context("write to file")
+    write_with(path, data, WriteOptions::default())
+}
+
+pub fn write_with(path: &str, data: Bytes, options: WriteOptions) -> Result<()> {
+    let mut pending = begin_write(path, options)?;
+    pending.write(&data)?;
+    pending.commit()
+}
+
+/// A write in progress: the data lands in the temporary file and only
+/// replaces the target on `commit`. Dropping it instead discards the data.
+#[derive(Debug)]
+pub struct PendingWrite {
+    file: F,
+    tmp: PathBuf,
+    target: PathBuf,
+    options: WriteOptions,
+    committed: bool,
+}
+
+impl PendingWrite {
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data).context("write to file")
+    }
+    pub fn commit(mut self) -> Result<()> {
+        self.file.sync_all().context("Sync file")?;
+        rename(&self.tmp, &self.target).context("Rename into place")?;
+        self.committed = true;
+        if self.options.sync_dir {
+            F::open(parent_dir(&self.target))
+                .and_then(|dir| dir.sync_all())
+                .context("Sync directory")?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PendingWrite {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = remove_file(&self.tmp);
+        }
+    }
+}
+
+pub fn begin_write(path: &str, options: WriteOptions) -> Result<PendingWrite> {
+    if options.create_dirs {
+        create(path)?;
+    }
+    let target = PathBuf::from(path);
+    let tmp = temp_path(&target);
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)
+        .context("Create temporary file")?;
+    Ok(PendingWrite {
+        file,
+        tmp,
+        target,
+        options,
+        committed: false,
+    })
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+// Unique per process and call, and hidden so listings do not pick it up.
+fn temp_path(target: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = target
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    parent_dir(target).join(format!(
+        ".{}.{}.{}.tmp",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
 }
 
 pub fn create_and_write(path: &str, data: Bytes) -> Result<()> {
-    create(path)?;
-    write(path, data)
+    write_with(path, data, WriteOptions::default().create_dirs())
+}
+
+/// Joins a request path below `root`, refusing anything that could step
+/// outside of it. A trailing slash is kept, it marks a directory.
+pub fn safe_join(root: &str, relative: &str) -> Option<String> {
+    let segments: Vec<&str> = relative.split('/').collect();
+    let (last, dirs) = segments.split_last()?;
+    let valid = |s: &&str| !s.is_empty() && *s != "." && *s != ".." && !s.contains('\\');
+    if !dirs.iter().all(valid) || !(last.is_empty() || valid(last)) {
+        return None;
+    }
+    Some(format!("{}/{}", root.trim_end_matches('/'), relative))
 }
 fn create(path: &str) -> Result<()> {
     let path = Path::new(path);
@@ -45,3 +319,36 @@ fn create(path: &str) -> Result<()> {
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_replaces_atomically() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("file-ops-{}", std::process::id()));
+        let path = dir.join("nested/target.txt");
+        let path = path.to_str().unwrap();
+        path.create_and_write(Bytes::from("first"))?;
+        path.write_with(Bytes::from("second"), WriteOptions::durable())?;
+        assert_eq!(path.read()?, Bytes::from("second"));
+        let leftovers = std::fs::read_dir(dir.join("nested"))
+            .context("list")?
+            .count();
+        assert_eq!(leftovers, 1);
+        std::fs::remove_dir_all(&dir).context("cleanup")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_safe_join() {
+        assert_eq!(
+            safe_join("/srv/", "a/b.txt"),
+            Some("/srv/a/b.txt".to_string())
+        );
+        assert_eq!(safe_join("/srv", "a/"), Some("/srv/a/".to_string()));
+        assert_eq!(safe_join("/srv", "a/../../etc/passwd"), None);
+        assert_eq!(safe_join("/srv", "a//b"), None);
+        assert_eq!(safe_join("/srv", ".."), None);
+    }
+}
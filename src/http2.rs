@@ -0,0 +1,716 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use crate::{
+    finished, mk_response, request_from_parts, BodyReader, Complete, Connection, ConnectionInfo,
+    Context, Deferred, Error, Header, Interim, Request, RequestBody, Response, ResponseBody,
+    Result, ServerConfig, State, StatusCode, StatusLine, MAX_BUFFERED_BODY,
+};
+
+/// What an HTTP/2 client sends first, on a connection it knows speaks it.
+pub const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame header length and the types looked at before h2 takes over.
+const FRAME_HEADER: usize = 9;
+const HEADERS: u8 = 0x1;
+const SETTINGS: u8 = 0x4;
+const END_STREAM_AND_HEADERS: u8 = 0x1 | 0x4;
+// Largest frame payload before the client has said otherwise.
+const MAX_FRAME: usize = 16 * 1024;
+const SEND_CHUNK: usize = 64 * 1024;
+
+/// Serves HTTP/2 on a connection until it closes. Each stream is mapped to a
+/// `Request` and its answer sent back, so the endpoint tree is the same as
+/// for HTTP/1.1. `upgraded` is the request that switched the connection
+/// over, answered on stream 1.
+pub(crate) async fn serve_h2<S, F, Fut>(
+    stream: S,
+    info: ConnectionInfo,
+    f: Arc<F>,
+    config: ServerConfig,
+    mut draining: watch::Receiver<bool>,
+    upgraded: Option<Arc<Request>>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(State) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<State>> + Send + 'static,
+{
+    let handshake = h2::server::Builder::new()
+        .max_header_list_size(config.max_header_bytes as u32)
+        .max_concurrent_streams(config.max_concurrent_streams)
+        .handshake::<_, Bytes>(stream);
+    let mut conn = tokio::time::timeout(config.header_timeout, handshake)
+        .await
+        .map_err(|_| Error::Rejected(StatusCode::SC408))?
+        .context("HTTP/2 handshake")?;
+    let mut upgraded = upgraded;
+    let mut streams = JoinSet::new();
+    let mut request_index = 0;
+    let mut closing = false;
+    loop {
+        let idle = streams.is_empty() && !closing;
+        tokio::select! {
+            next = conn.accept() => {
+                let Some(next) = next else { break };
+                let (req, respond) = next.context("HTTP/2 stream")?;
+                let info = ConnectionInfo {
+                    request_index,
+                    ..info.clone()
+                };
+                request_index += 1;
+                let request = match u32::from(respond.stream_id()) {
+                    1 => upgraded.take(),
+                    _ => None,
+                };
+                let f = Arc::clone(&f);
+                streams.spawn(serve_stream(req, respond, request, info, f, config));
+            }
+            Some(_) = streams.join_next(), if !streams.is_empty() => {}
+            _ = draining.wait_for(|draining| *draining), if !closing => {
+                conn.graceful_shutdown();
+                closing = true;
+            }
+            _ = tokio::time::sleep(config.idle_timeout), if idle => {
+                conn.graceful_shutdown();
+                closing = true;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn serve_stream<F, Fut>(
+    req: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    upgraded: Option<Arc<Request>>,
+    info: ConnectionInfo,
+    f: Arc<F>,
+    config: ServerConfig,
+) -> Result<()>
+where
+    F: Fn(State) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<State>> + Send + 'static,
+{
+    let (parts, body) = req.into_parts();
+    let mut body = RecvBody {
+        recv: body,
+        pending: Bytes::new(),
+    };
+    let request = match upgraded {
+        Some(request) => Ok(request),
+        None => read_request(parts, &mut body, info, &config)
+            .await
+            .map(Arc::new),
+    };
+    let (resp, head) = match request {
+        Ok(request) => {
+            let head = request.http_method().is_head();
+            (handle(request, f.as_ref(), body, &config).await, head)
+        }
+        Err(Error::Rejected(code)) => (mk_response("", code).into_inner(), false),
+        Err(e) => return Err(e),
+    };
+    send_response(&mut respond, resp, head, config.write_timeout).await
+}
+
+/// Takes in a stream's body as HTTP/1.1 does: one with a length over
+/// `MAX_BUFFERED_BODY` is left for a `BodyReader`, smaller ones are read
+/// now. Without a length a body is read up to `MAX_BUFFERED_BODY`, past it
+/// the stream is answered 411.
+async fn read_request(
+    parts: http::request::Parts,
+    body: &mut RecvBody,
+    info: ConnectionInfo,
+    config: &ServerConfig,
+) -> Result<Request> {
+    let target = parts
+        .uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let authority = parts.uri.authority().map(|a| a.as_str().as_bytes());
+    let headers = parts
+        .headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_bytes()))
+        .chain(authority.map(|a| ("host", a)));
+    let mut request = request_from_parts(parts.method.as_str(), target, headers, *config)?;
+    request.connection = Some(info);
+    let len = request.headers.content_length().map(|len| *len);
+    if len.is_some_and(|len| len > config.max_body) {
+        return Err(Error::Rejected(StatusCode::SC413));
+    }
+    if let Some(len) = len.filter(|len| *len > MAX_BUFFERED_BODY) {
+        request.body = Some(RequestBody::Stream(len.into()));
+        return Ok(request);
+    }
+
+    let mut full = BytesMut::new();
+    loop {
+        let data = tokio::time::timeout(config.body_timeout, body.recv.data())
+            .await
+            .map_err(|_| Error::Rejected(StatusCode::SC408))?;
+        let Some(data) = data else { break };
+        let data = data.context("HTTP/2 body")?;
+        let _ = body.recv.flow_control().release_capacity(data.len());
+        let len = (full.len() + data.len()) as u64;
+        if len > config.max_body {
+            return Err(Error::Rejected(StatusCode::SC413));
+        }
+        if len > MAX_BUFFERED_BODY {
+            return Err(Error::Rejected(StatusCode::SC411));
+        }
+        full.extend_from_slice(&data);
+    }
+    // Endpoints size bodies by their Content-Length, which HTTP/2 leaves
+    // optional.
+    if request.headers.content_length().is_none() && !full.is_empty() {
+        let mut headers: Vec<Header> = request.headers.iter().cloned().collect();
+        headers.push(Header::content_length(full.len() as u64));
+        request.headers = headers.into();
+    }
+    request.body = Some(RequestBody::Full(full.freeze()));
+    Ok(request)
+}
+
+async fn handle<F, Fut>(
+    request: Arc<Request>,
+    f: &F,
+    mut body: RecvBody,
+    config: &ServerConfig,
+) -> Response
+where
+    F: Fn(State) -> Fut,
+    Fut: Future<Output = Result<State>>,
+{
+    let state = match f(State::incomplete(Arc::clone(&request))).await {
+        Ok(state) => state,
+        Err(_) => return mk_response("", StatusCode::SC500).into_inner(),
+    };
+    match state {
+        // A stream has to be answered; nothing here would.
+        State::Incomplete(_) => mk_response("", StatusCode::SC404).into_inner(),
+        State::Complete(Complete(_, resp)) => resp.into_inner(),
+        // A stream can not be taken over, HTTP/2 has no Upgrade.
+        State::Upgrading(_) => mk_response("", StatusCode::SC501).into_inner(),
+        State::Deferred(Deferred(req, handler)) => {
+            let result = match req.body() {
+                Some(RequestBody::Stream(len)) => {
+                    let (mut remaining, mut buffered) = (*len, BytesMut::new());
+                    let body = BodyReader::new(&mut body, &mut buffered, &mut remaining)
+                        .with_timeout(config.body_timeout);
+                    handler.handle(body).await
+                }
+                body => {
+                    let full = body.and_then(|b| b.bytes()).unwrap_or_default();
+                    let mut remaining = full.len() as u64;
+                    let mut full = BytesMut::from(full);
+                    let mut empty = tokio::io::empty();
+                    let body = BodyReader::new(&mut empty, &mut full, &mut remaining);
+                    handler.handle(body).await
+                }
+            };
+            match result {
+                Ok(resp) => resp,
+                Err(Error::Rejected(code)) => mk_response("", code).into_inner(),
+                Err(_) => mk_response("", StatusCode::SC500).into_inner(),
+            }
+        }
+    }
+}
+
+async fn send_response(
+    respond: &mut SendResponse<Bytes>,
+    resp: Response,
+    head: bool,
+    write_timeout: Duration,
+) -> Result<()> {
+    for Interim(status_line, headers) in &resp.3 {
+        respond
+            .send_informational(parts(status_line, headers)?)
+            .context("HTTP/2 interim response")?;
+    }
+    let parts = parts(&resp.0, &resp.1)?;
+    let body = resp.2.filter(|body| !head && !body.is_empty());
+    let mut send = respond
+        .send_response(parts, body.is_none())
+        .context("HTTP/2 response")?;
+    match body {
+        None => Ok(()),
+        Some(ResponseBody::Full(bytes)) => send_data(&mut send, bytes, true, write_timeout).await,
+        Some(ResponseBody::File(file)) => {
+            use std::os::unix::fs::FileExt;
+            let mut offset = 0;
+            while offset < file.len() {
+                let want = (file.len() - offset).min(SEND_CHUNK as u64) as usize;
+                let mut chunk = vec![0; want];
+                file.file()
+                    .read_exact_at(&mut chunk, offset)
+                    .context("Reading file")?;
+                offset += want as u64;
+                let last = offset == file.len();
+                send_data(&mut send, chunk.into(), last, write_timeout).await?;
+            }
+            Ok(())
+        }
+        Some(ResponseBody::Stream(body)) => {
+            let (mut chunks, mut producer) = body.start();
+            while let Some(chunk) = chunks.recv().await {
+                send_data(&mut send, chunk, false, write_timeout).await?;
+            }
+            // Cut short by the producer, the body must not look complete.
+            match finished(&mut producer).await {
+                true => send.send_data(Bytes::new(), true).context("HTTP/2 data"),
+                false => {
+                    send.send_reset(h2::Reason::INTERNAL_ERROR);
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+fn parts(status_line: &StatusLine, headers: &[Header]) -> Result<http::Response<()>> {
+    let code: u16 = std::str::from_utf8(status_line.status_code().as_bytes())?.parse()?;
+    let mut builder = http::Response::builder().status(code);
+    for header in headers {
+        // Connection handling is HTTP/1.1's, HTTP/2 forbids the header.
+        if matches!(header, Header::Connection(_)) {
+            continue;
+        }
+        let line = header.to_string();
+        if let Some((name, value)) = line.split_once(':') {
+            builder = builder.header(name, value.trim());
+        }
+    }
+    builder.body(()).context("HTTP/2 response")
+}
+
+// Sends as the peer's flow control allows, rather than queueing it all. A
+// peer that opens no window for `stall` ends it, however long the whole takes.
+async fn send_data(
+    send: &mut SendStream<Bytes>,
+    mut data: Bytes,
+    end: bool,
+    stall: Duration,
+) -> Result<()> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let room = tokio::time::timeout(stall, std::future::poll_fn(|cx| send.poll_capacity(cx)))
+            .await
+            .map_err(|_| Error::GeneralError("HTTP/2 write timed out".to_string()))?
+            .ok_or_else(|| Error::GeneralError("HTTP/2 stream closed".to_string()))?
+            .context("HTTP/2 flow control")?;
+        let chunk = data.split_to(room.min(data.len()));
+        send.send_data(chunk, end && data.is_empty())
+            .context("HTTP/2 data")?;
+    }
+    Ok(())
+}
+
+/// Whether a new connection opens with the HTTP/2 preface, reading until
+/// that is known. Whatever was read stays in `buffered`.
+pub(crate) async fn starts_with_preface<S>(
+    stream: &mut S,
+    buffered: &mut BytesMut,
+    config: &ServerConfig,
+) -> Result<bool>
+where
+    S: AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+    let read = async {
+        while buffered.len() < H2_PREFACE.len() && H2_PREFACE.starts_with(buffered) {
+            if stream.read_buf(buffered).await.context("Read preface")? == 0 {
+                return Ok(false);
+            }
+        }
+        Ok(buffered.starts_with(H2_PREFACE))
+    };
+    tokio::time::timeout(config.header_timeout, read)
+        .await
+        .map_err(|_| Error::Rejected(StatusCode::SC408))?
+}
+
+/// Whether an HTTP/1.1 request asks to switch to HTTP/2 in the clear, and
+/// can: it has no body and its target fits a frame.
+pub(crate) fn wants_h2c(request: &Request) -> bool {
+    request.takes_interim()
+        && request.headers.connection() == Some(Connection::Upgrade)
+        && request
+            .headers
+            .upgrade()
+            .is_some_and(|protocol| protocol.eq_ignore_ascii_case("h2c"))
+        && request.headers.content_length().map_or(0, |len| *len) == 0
+        && upgraded_headers(request).len() <= MAX_FRAME
+}
+
+/// After the 101 of an `Upgrade: h2c`, reads the client's preface and first
+/// SETTINGS frame and puts a HEADERS frame for stream 1 behind them, so the
+/// HTTP/2 side sees the upgraded request as the stream it is answered on.
+/// The `HTTP2-Settings` the client sent along are not applied; it repeats
+/// them in its own SETTINGS frame anyway.
+pub(crate) async fn h2c_preface<S>(
+    stream: &mut S,
+    buffered: &mut BytesMut,
+    request: &Request,
+    config: &ServerConfig,
+) -> Result<Bytes>
+where
+    S: AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+    let read = async {
+        loop {
+            if buffered.len() >= H2_PREFACE.len() + FRAME_HEADER {
+                let frame = &buffered[H2_PREFACE.len()..];
+                let len = u32::from_be_bytes([0, frame[0], frame[1], frame[2]]) as usize;
+                if !buffered.starts_with(H2_PREFACE) || frame[3] != SETTINGS {
+                    return Err(Error::GeneralError("Not an HTTP/2 preface".to_string()));
+                }
+                if buffered.len() >= H2_PREFACE.len() + FRAME_HEADER + len {
+                    return Ok(H2_PREFACE.len() + FRAME_HEADER + len);
+                }
+            }
+            if stream.read_buf(buffered).await.context("Read preface")? == 0 {
+                return Err(Error::GeneralError("Closed before the preface".to_string()));
+            }
+        }
+    };
+    let settings_end = tokio::time::timeout(config.header_timeout, read)
+        .await
+        .map_err(|_| Error::GeneralError("HTTP/2 preface timed out".to_string()))??;
+    let mut prefix = buffered.split_to(settings_end);
+    let payload = upgraded_headers(request);
+    prefix.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    prefix.extend_from_slice(&[HEADERS, END_STREAM_AND_HEADERS]);
+    prefix.extend_from_slice(&1u32.to_be_bytes());
+    prefix.extend_from_slice(&payload);
+    prefix.extend_from_slice(&buffered.split());
+    Ok(prefix.freeze())
+}
+
+// The pseudo headers of the upgraded request. Its real headers are not
+// needed, the parsed request is what stream 1 is answered from.
+fn upgraded_headers(request: &Request) -> Vec<u8> {
+    let mut block = vec![];
+    let target = request.request_line.1 .0.as_str();
+    let authority = request.headers.host();
+    let fields = [
+        Some((":method", request.http_method().as_str())),
+        Some((":scheme", "http")),
+        Some((":path", target)),
+        authority.as_ref().map(|host| (":authority", host.as_str())),
+    ];
+    for (name, value) in fields.into_iter().flatten() {
+        // Literal without indexing, new name, no Huffman coding.
+        block.push(0);
+        hpack_string(&mut block, name.as_bytes());
+        hpack_string(&mut block, value.as_bytes());
+    }
+    block
+}
+
+fn hpack_string(block: &mut Vec<u8>, value: &[u8]) {
+    // Lengths are integers with a 7 bit prefix.
+    let mut len = value.len();
+    if len < 0x7f {
+        block.push(len as u8);
+    } else {
+        block.push(0x7f);
+        len -= 0x7f;
+        while len >= 0x80 {
+            block.push((len % 0x80) as u8 | 0x80);
+            len /= 0x80;
+        }
+        block.push(len as u8);
+    }
+    block.extend_from_slice(value);
+}
+
+/// A stream's body as bytes, for a `BodyReader`. Received data is let in
+/// again as it is passed on.
+struct RecvBody {
+    recv: RecvStream,
+    pending: Bytes,
+}
+
+impl AsyncRead for RecvBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        // An empty DATA frame is no end of the body.
+        while self.pending.is_empty() {
+            match std::task::ready!(self.recv.poll_data(cx)) {
+                None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(std::io::Error::other(e))),
+                Some(Ok(data)) => {
+                    let _ = self.recv.flow_control().release_capacity(data.len());
+                    self.pending = data;
+                }
+            }
+        }
+        let n = self.pending.len().min(buf.remaining());
+        buf.put_slice(&self.pending.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A stream with some of its input already read, which is given out again
+/// before the rest.
+pub(crate) struct Rewind<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub(crate) fn new(prefix: Bytes, inner: S) -> Self {
+        Rewind { prefix, inner }
+    }
+    /// The bytes not read yet and the stream behind them.
+    pub(crate) fn into_parts(self) -> (Bytes, S) {
+        (self.prefix, self.inner)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.prefix.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let n = self.prefix.len().min(buf.remaining());
+        buf.put_slice(&self.prefix.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lift, ok, serve_connection, state, BodyHandler, Endpoint};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    fn routes() -> Arc<impl Fn(State) -> std::future::Ready<Result<State>>> {
+        Arc::new(|s: State| {
+            let req = s.request();
+            let body = req.body().and_then(|b| b.bytes()).unwrap_or_default();
+            let answer = format!(
+                "{} {} {}",
+                req.http_method().as_str(),
+                req.get_route(),
+                String::from_utf8_lossy(&body)
+            );
+            std::future::ready(state().set_response(ok(answer)).handle(s).map(|(s, _)| s))
+        })
+    }
+
+    fn serve(server: DuplexStream) -> tokio::task::JoinHandle<Result<()>> {
+        let info = ConnectionInfo::new(None, None);
+        tokio::spawn(serve_connection(server, info, routes(), Default::default()))
+    }
+
+    async fn fetch(
+        client: &h2::client::SendRequest<Bytes>,
+        method: &str,
+        uri: &str,
+        body: &'static str,
+    ) -> Result<(u16, String)> {
+        let req = http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(())
+            .context("request")?;
+        let mut client = client.clone().ready().await.context("ready")?;
+        let (resp, mut send) = client.send_request(req, body.is_empty()).context("send")?;
+        if !body.is_empty() {
+            send.send_data(Bytes::from(body), true).context("data")?;
+        }
+        let resp = resp.await.context("response")?;
+        let status = resp.status().as_u16();
+        let mut body = resp.into_body();
+        let mut received = vec![];
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.context("body")?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            received.extend_from_slice(&chunk);
+        }
+        Ok((status, String::from_utf8_lossy(&received).to_string()))
+    }
+
+    #[tokio::test]
+    async fn test_prior_knowledge() -> Result<()> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let serving = serve(server);
+        let (client, conn) = h2::client::handshake(client).await.context("handshake")?;
+        tokio::spawn(conn);
+        // Both streams open at once on the one connection.
+        let (get, post) = tokio::join!(
+            fetch(&client, "GET", "http://localhost/a", ""),
+            fetch(&client, "POST", "http://localhost/b", "hello"),
+        );
+        assert_eq!(get?, (200, "GET /a ".to_string()));
+        assert_eq!(post?, (200, "POST /b hello".to_string()));
+        let (status, _) = fetch(&client, "PATCH", "http://localhost/", "").await?;
+        assert_eq!(status, 400);
+        drop(client);
+        serving.await.context("join")??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_large_body() -> Result<()> {
+        // Counts the body as it comes, it is never held whole.
+        let count = BodyHandler::new(|mut body| {
+            Box::pin(async move {
+                let mut len = 0;
+                while let Some(chunk) = body.chunk().await? {
+                    len += chunk.len();
+                }
+                Ok(mk_response(len.to_string(), StatusCode::SC200).into_inner())
+            })
+        });
+        let f = Arc::new(move |s: State| {
+            let routes = state().set_body_handler(lift(count.clone()));
+            std::future::ready(routes.handle(s).map(|(s, _)| s))
+        });
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let info = ConnectionInfo::new(None, None);
+        let config = ServerConfig {
+            max_concurrent_streams: 8,
+            ..Default::default()
+        };
+        tokio::spawn(serve_connection(server, info, f, config));
+        let (client, conn) = h2::client::handshake(client).await.context("handshake")?;
+        tokio::spawn(conn);
+        let mut client = client.ready().await.context("ready")?;
+
+        let len = 2 * MAX_BUFFERED_BODY as usize;
+        for (length, status, answer) in [(true, 200, len.to_string()), (false, 411, "".into())] {
+            let mut req = http::Request::builder()
+                .method("PUT")
+                .uri("http://localhost/upload");
+            if length {
+                req = req.header("content-length", len);
+            }
+            let req = req.body(()).context("request")?;
+            let (resp, mut send) = client.send_request(req, false).context("send")?;
+            send.send_data(Bytes::from(vec![b'x'; len]), true)
+                .context("data")?;
+            let resp = resp.await.context("response")?;
+            assert_eq!(resp.status().as_u16(), status);
+            let mut body = resp.into_body();
+            let mut received = vec![];
+            while let Some(chunk) = body.data().await {
+                received.extend_from_slice(&chunk.context("body")?);
+            }
+            assert_eq!(String::from_utf8_lossy(&received), answer);
+            client = client.ready().await.context("ready")?;
+        }
+        // By now the server's settings are in.
+        assert_eq!(client.current_max_send_streams(), 8);
+        Ok(())
+    }
+
+    // Frames after the switch, read until stream 1 ends; h2's client can
+    // not take over an upgraded connection.
+    async fn read_frame(stream: &mut DuplexStream) -> Result<(u8, u8, u32, Vec<u8>)> {
+        let mut header = [0; FRAME_HEADER];
+        stream.read_exact(&mut header).await.context("frame")?;
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.context("payload")?;
+        Ok((header[3], header[4], id, payload))
+    }
+
+    #[tokio::test]
+    async fn test_h2c_upgrade() -> Result<()> {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let serving = serve(server);
+        client
+            .write_all(
+                b"GET /up HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+                  Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n",
+            )
+            .await
+            .context("write")?;
+        let mut switched = vec![0; 1024];
+        let n = client.read(&mut switched).await.context("read")?;
+        let switched = String::from_utf8_lossy(&switched[..n]).to_string();
+        assert!(switched.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(switched.contains("Upgrade: h2c\r\n") && switched.ends_with("\r\n\r\n"));
+
+        client.write_all(H2_PREFACE).await.context("write")?;
+        // An empty SETTINGS frame.
+        client
+            .write_all(&[0, 0, 0, SETTINGS, 0, 0, 0, 0, 0])
+            .await
+            .context("write")?;
+        let mut body = vec![];
+        loop {
+            let (kind, flags, id, payload) = read_frame(&mut client).await?;
+            if id != 1 {
+                continue;
+            }
+            // The first header is `:status: 200`, static table index 8.
+            if kind == HEADERS {
+                assert_eq!(payload[0], 0x88);
+            } else {
+                body.extend_from_slice(&payload);
+            }
+            if flags & 0x1 != 0 {
+                break;
+            }
+        }
+        assert_eq!(String::from_utf8_lossy(&body), "GET /up ");
+        drop(client);
+        let _ = serving.await;
+        Ok(())
+    }
+}
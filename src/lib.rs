@@ -1,15 +1,35 @@
+mod conditional;
+mod connections;
 mod endpoint;
 mod error;
 mod file;
+mod http2;
 mod parsers;
 mod request;
+mod sendfile;
 mod server;
+mod sse;
+mod stream;
+mod tls;
+mod tunnel;
 mod types;
+mod upgrade;
+mod websocket;
 
+pub use conditional::*;
+pub use connections::*;
 pub use endpoint::*;
 pub use error::*;
 pub use file::*;
+pub use http2::*;
 pub use parsers::*;
 pub use request::*;
+pub use sendfile::*;
 pub use server::*;
+pub use sse::*;
+pub use stream::*;
+pub use tls::*;
+pub use tunnel::*;
 pub use types::*;
+pub use upgrade::*;
+pub use websocket::*;
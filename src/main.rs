@@ -1,27 +1,45 @@
 #[allow(unused_imports)]
 use std::sync::Arc;
 
+use tokio::signal::unix::{signal, SignalKind};
+
 use codecrafters_http_server::{
-    close_connection, gzip, lift, mk_response, not_found, ok, path, req_body, route, state,
-    user_agent as get_user_agent, Endpoint, FileOps, RequestBody, Result, Serve, Server,
-    StatusCode, UnitT, UserAgent,
+    close_connection, delete_file as remove_file, gzip, not_found, ok, path, route, serve_file,
+    state, store_file, user_agent as get_user_agent, Endpoint, Result, Serve, Server, UnitT,
+    UserAgent,
 };
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let server = Server::bind("127.0.0.1:4221").await?;
     server
-        .serve(Arc::new(async move |state| {
-            routes().handle(state).map(|v| v.0)
-        }))
+        .serve_with_shutdown(
+            Arc::new(async move |state| routes().handle(state).map(|v| v.0)),
+            shutdown_signal(),
+        )
         .await
 }
 
+// SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => terminate.recv().await,
+            Err(_) => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
 pub fn routes() -> impl Endpoint<Output = UnitT> {
     let v = user_agent()
-        .or(route::get("/echo").set_response(path().flat_map(|v| ok(v))))
+        .or(route::get("/echo").set_response(path().flat_map(ok)))
         .or(get_file())
         .or(post_file())
+        .or(delete_file())
         .or(route::get("/").set_response(ok("")))
         .or(state().set_response(not_found("")))
         .and(gzip().and(close_connection()))
@@ -35,31 +53,19 @@ fn user_agent() -> impl Endpoint<Output = UnitT> {
     route::get("/user-agent").set_response(get_user_agent().flat_map(response))
 }
 
+const FILES_ROOT: &str = "/tmp/data/codecrafters.io/http-server-tester";
+
 fn get_file() -> impl Endpoint<Output = UnitT> {
-    let read = |file: String| {
-        lift(
-            match format!("/tmp/data/codecrafters.io/http-server-tester/{}", file)
-                .as_str()
-                .read()
-            {
-                Ok(data) => mk_response(data, StatusCode::SC200),
-                Err(_) => mk_response("", StatusCode::SC404),
-            },
-        )
-    };
+    let read = |file: String| serve_file(FILES_ROOT, &file);
     route::get("/files").set_response(path().flat_map(read))
 }
 fn post_file() -> impl Endpoint<Output = UnitT> {
-    let response = |(file, body): (String, Option<RequestBody>)| {
-        lift(
-            match format!("/tmp/data/codecrafters.io/http-server-tester/{}", file)
-                .as_str()
-                .write(body.unwrap().0)
-            {
-                Ok(_) => mk_response("", StatusCode::SC201),
-                Err(_) => mk_response("", StatusCode::SC404),
-            },
-        )
-    };
-    route::post("/files").set_response(path().and(req_body()).flat_map(response))
+    let write = |file: String| store_file(FILES_ROOT, &file);
+    route::post("/files")
+        .or(route::put("/files"))
+        .set_body_handler(path().flat_map(write))
+}
+fn delete_file() -> impl Endpoint<Output = UnitT> {
+    let delete = |file: String| remove_file(FILES_ROOT, &file);
+    route::delete("/files").set_body_handler(path().flat_map(delete))
 }
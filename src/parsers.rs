@@ -1,22 +1,25 @@
-use bytes::Bytes;
+use bytes::{Buf, BytesMut};
 use nom::branch::alt;
-use nom::bytes::complete::{tag, take_while};
-use nom::bytes::{is_not, take_until};
+use nom::bytes::complete::{tag, take_while1};
+use nom::bytes::{is_not, streaming, take_until};
 use nom::character::complete::{crlf, space0, space1};
-use nom::combinator::{map, map_parser, rest};
-use nom::multi::{many0, separated_list0};
+use nom::combinator::{map, recognize};
 use nom::sequence::{preceded, terminated};
 use nom::{IResult, Parser};
 
 use crate::{
-    Connection, ContentType, Encoding, Error, Header, Headers, HttpMethod, Request, RequestBody,
-    RequestLine, RequestTarget, Result,
+    Connection, ContentType, Encoding, EntityTags, Error, Expect, Header, HttpDate, HttpMethod,
+    IfRange, Request, RequestBody, RequestLine, RequestTarget, Result, ServerConfig, StatusCode,
 };
 
 fn parse_http_method(input: &[u8]) -> IResult<&[u8], crate::types::HttpMethod> {
     alt((
         map(tag(&b"GET"[..]), |_| HttpMethod::Get),
         map(tag(&b"POST"[..]), |_| HttpMethod::Post),
+        map(tag(&b"PUT"[..]), |_| HttpMethod::Put),
+        map(tag(&b"DELETE"[..]), |_| HttpMethod::Delete),
+        map(tag(&b"HEAD"[..]), |_| HttpMethod::Head),
+        map(tag(&b"CONNECT"[..]), |_| HttpMethod::Connect),
     ))
     .parse(input)
 }
@@ -27,9 +30,11 @@ fn parse_target(input: &[u8]) -> IResult<&[u8], RequestTarget> {
         .map(RequestTarget)
         .parse(input)
 }
+/// Anything but HTTP/1.0 is taken for 1.1.
 fn parse_http_version(input: &[u8]) -> IResult<&[u8], crate::types::HttpVersion> {
-    map(take_until(&b"\r\n"[..]), |_| {
-        crate::types::HttpVersion::HttpOne
+    map(take_until(&b"\r\n"[..]), |v: &[u8]| match v {
+        b"HTTP/1.0" => crate::types::HttpVersion::HttpOneZero,
+        _ => crate::types::HttpVersion::HttpOne,
     })
     .parse(input)
 }
@@ -45,105 +50,262 @@ fn parse_request_line(input: &[u8]) -> IResult<&[u8], RequestLine> {
         .map(|(rest, (m, _, t, _, v))| (rest, RequestLine(m, t, v)))
 }
 
-fn parse_headers(input: &[u8]) -> IResult<&[u8], Vec<Header>> {
-    fn to_string(
-        mut f: impl FnMut(String) -> Result<Option<Header>>,
-    ) -> impl FnMut((&[u8], &[u8])) -> Result<Option<Header>> {
-        move |(_, b)| f(String::from_utf8(b.to_vec())?)
-    }
-    let host =
-        (tag(&b"Host: "[..]), rest).map_res(to_string(|v| Ok(Some(Header::host(v.as_str())))));
-
-    let user_agent = (tag(&b"User-Agent: "[..]), rest)
-        .map_res(to_string(|v| Ok(Some(Header::user_agent(v.as_str())))));
-
-    let accept =
-        (tag(&b"Accept: "[..]), rest).map_res(to_string(|v| Ok(Some(Header::accept(v.as_str())))));
-    let content_type = (tag(&b"Content-Type: "[..]), rest).map_res(to_string(|v| {
-        ContentType::from2(v.as_str()).map(|v| Some(Header::content_type(v)))
-    }));
-    let content_length = (tag(&b"Content-Length: "[..]), rest)
-        .map_res(to_string(|v| Ok(Some(Header::content_length(v.parse()?)))));
-
-    let content_encoding = (tag(&b"Content-Encoding: "[..]), rest).map_res(to_string(|v| {
-        Ok(Some(Header::content_encoding(Encoding::from(v.as_str())?)))
-    }));
-
-    let encoding = map(
-        separated_list0(
-            tag(","),
-            preceded(space0, terminated(take_while(|c: u8| c != b','), space0)),
-        ),
-        |v: Vec<&[u8]>| {
-            v.into_iter()
-                .flat_map(|enc| match String::from_utf8(enc.to_vec()) {
-                    Ok(s) => Encoding::from(s.as_str()).ok(),
-                    Err(_) => None,
-                })
+// A character of a field name, RFC 9110's `tchar`.
+fn is_tchar(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+/// One header line, including its CRLF: `name ":" OWS value OWS`. Names are
+/// matched in any case, as RFC 9110 has them. Headers we do not use for
+/// anything parse to `None`.
+fn parse_header(input: &[u8]) -> IResult<&[u8], Option<Header>> {
+    parse_field
+        .map_res(|(name, value)| header(name, value))
+        .parse(input)
+}
+
+fn parse_field(input: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
+    let value = take_until(&b"\r\n"[..]).map(|v: &[u8]| v.trim_ascii_end());
+    (
+        terminated(take_while1(is_tchar), tag(&b":"[..])),
+        preceded(space0, terminated(value, tag(&b"\r\n"[..]))),
+    )
+        .parse(input)
+}
+
+/// The header a field stands for. A value a known header refuses fails the
+/// line rather than passing it for a header we do not know.
+fn header(name: &[u8], value: &[u8]) -> Result<Option<Header>> {
+    let v = String::from_utf8(value.to_vec())?;
+    let header = match name.to_ascii_lowercase().as_slice() {
+        b"host" => Header::host(&v),
+        b"user-agent" => Header::user_agent(&v),
+        b"accept" => Header::accept(&v),
+        // A type we have no name for is as good as none.
+        b"content-type" => return Ok(ContentType::from2(&v).ok().map(Header::content_type)),
+        // Digits only, `+5` is not a length.
+        b"content-length" => match v.bytes().all(|c| c.is_ascii_digit()) {
+            true => Header::content_length(v.parse()?),
+            false => return Err(parse_error()),
         },
-    );
-
-    let accept_encoding = (tag(&b"Accept-Encoding: "[..]), encoding).map_res(|(_, b)| {
-        let bb: Vec<Encoding> = b.collect();
-        if !bb.is_empty() {
-            Ok::<Option<Header>, Error>(Some(Header::accept_encoding(&bb)))
-        } else {
-            Ok(None)
+        // Without chunked request bodies, whatever follows could only be
+        // taken for the next request.
+        b"transfer-encoding" => return Err(Error::Rejected(StatusCode::SC501)),
+        b"content-encoding" => Header::content_encoding(Encoding::from(&v)?),
+        b"accept-encoding" => {
+            let encodings: Vec<Encoding> = v
+                .split(',')
+                .filter_map(|enc| Encoding::from(enc.trim()).ok())
+                .collect();
+            if encodings.is_empty() {
+                return Ok(None);
+            }
+            Header::accept_encoding(&encodings)
+        }
+        // Anything but an upgrade ends the connection after this request.
+        b"connection" => {
+            let upgrade = v
+                .split(',')
+                .any(|option| option.trim().eq_ignore_ascii_case("upgrade"));
+            Header::connection(match upgrade {
+                true => Connection::Upgrade,
+                false => Connection::Close,
+            })
+        }
+        b"upgrade" => Header::upgrade(&v),
+        b"sec-websocket-key" => Header::sec_websocket_key(&v),
+        b"sec-websocket-version" => Header::sec_websocket_version(&v),
+        b"sec-websocket-extensions" => Header::sec_websocket_extensions(&v),
+        b"expect" => Header::expect(match v.eq_ignore_ascii_case("100-continue") {
+            true => Expect::Continue,
+            false => Expect::Other(v),
+        }),
+        b"last-event-id" => Header::last_event_id(&v),
+        // An unparseable condition is void, the request stands.
+        b"if-none-match" => return Ok(EntityTags::parse(&v).ok().map(Header::if_none_match)),
+        b"if-modified-since" => return Ok(HttpDate::parse(&v).ok().map(Header::if_modified_since)),
+        b"if-match" => return Ok(EntityTags::parse(&v).ok().map(Header::if_match)),
+        b"if-unmodified-since" => {
+            return Ok(HttpDate::parse(&v).ok().map(Header::if_unmodified_since))
         }
-    });
-
-    let connection = (tag(&"Connection: "[..]), rest).map_res(to_string(|_| {
-        Ok(Some(Header::connection(Connection::Close)))
-    }));
-
-    let (input, headers) = many0(map(
-        (
-            map_parser(
-                take_until(&b"\r\n"[..]),
-                alt((
-                    accept_encoding,
-                    host,
-                    user_agent,
-                    accept,
-                    content_type,
-                    content_length,
-                    content_encoding,
-                    connection,
-                )),
-            ),
-            tag(&b"\r\n"[..]),
-        ),
-        |(a, _)| a,
+        b"if-range" => return Ok(IfRange::parse(&v).ok().map(Header::if_range)),
+        _ => return Ok(None),
+    };
+    Ok(Some(header))
+}
+
+// A whole line, CRLF included. Streaming: without a CRLF it is `Incomplete`.
+fn parse_line(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    recognize((
+        streaming::take_until(&b"\r\n"[..]),
+        streaming::tag(&b"\r\n"[..]),
     ))
-    .parse(input)?;
-    Ok((input, headers.into_iter().flatten().collect()))
+    .parse(input)
 }
 
-pub fn parse_request(input: &[u8]) -> Result<Request> {
-    let res = map(
-        (parse_request_line, crlf, parse_headers, crlf, rest),
-        |(request_line, _, headers, _, body)| {
-            let h: Headers = headers.into();
-            Request {
-                request_line,
-                headers: h,
-                body: Some(RequestBody(Bytes::from(body.to_vec()))),
+/// Parses a request head as its bytes arrive. Each call consumes the complete
+/// lines at the front of the buffer and keeps what it learned from them, so
+/// no byte is looked at twice. Whatever follows the head, a body or the next
+/// request, stays in the buffer.
+#[derive(Debug, Default)]
+pub struct RequestParser {
+    config: ServerConfig,
+    request_line: Option<RequestLine>,
+    headers: Vec<Header>,
+    header_count: usize,
+    header_bytes: usize,
+    // How much of the buffer is known not to contain a line end.
+    scanned: usize,
+}
+
+impl RequestParser {
+    /// A parser enforcing the head limits of `config`.
+    pub fn new(config: ServerConfig) -> Self {
+        RequestParser {
+            config,
+            ..Default::default()
+        }
+    }
+    // Checked for complete lines as well as for the start of one still
+    // arriving, so a client cannot make the buffer grow past a limit.
+    fn check_limits(&self, line: usize) -> Result<()> {
+        let config = &self.config;
+        match self.request_line {
+            None if line > config.max_request_line => Err(Error::Rejected(StatusCode::SC414)),
+            Some(_)
+                if self.header_count > config.max_headers
+                    || self.header_bytes + line > config.max_header_bytes =>
+            {
+                Err(Error::Rejected(StatusCode::SC431))
             }
-        },
-    )
-    .parse(input);
-    match res {
-        Ok((_, request)) => Ok(request),
-        Err(_) => Err(Error::GeneralError("Parser error".to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// `Ok(None)` means more input is needed, an error that no amount of it
+    /// would make this a valid request.
+    pub fn parse(&mut self, buf: &mut BytesMut) -> Result<Option<Request>> {
+        loop {
+            // The CR of a line end might be the last byte scanned.
+            let from = self.scanned.saturating_sub(1);
+            if !buf[from..].windows(2).any(|w| w == b"\r\n") {
+                self.scanned = buf.len();
+                self.check_limits(buf.len())?;
+                return Ok(None);
+            }
+            let line = match parse_line(buf) {
+                Ok((_, line)) => line,
+                Err(nom::Err::Incomplete(_)) => return Ok(None),
+                Err(_) => return Err(parse_error()),
+            };
+            let len = line.len();
+            let done = line == b"\r\n" && self.request_line.is_some();
+            if !done {
+                self.header_count += self.request_line.is_some() as usize;
+                self.check_limits(len)?;
+            }
+            match self.request_line {
+                None => {
+                    let (_, request_line) = terminated(parse_request_line, crlf)
+                        .parse(line)
+                        .map_err(|_| parse_error())?;
+                    self.request_line = Some(request_line);
+                }
+                Some(_) if done => {}
+                Some(_) => {
+                    let (_, (name, value)) = parse_field(line).map_err(|_| parse_error())?;
+                    let header = header(name, value).map_err(|e| match e {
+                        Error::Rejected(code) => Error::Rejected(code),
+                        _ => parse_error(),
+                    })?;
+                    self.headers.extend(header);
+                    self.header_bytes += len;
+                }
+            }
+            buf.advance(len);
+            self.scanned = 0;
+            if done {
+                let request_line = self.request_line.take().ok_or_else(parse_error)?;
+                // Two lengths that disagree leave the body's end to a guess.
+                let mut lengths = self.headers.iter().filter_map(|h| match h {
+                    Header::ContentLength(l) => Some(**l),
+                    _ => None,
+                });
+                if let Some(first) = lengths.next() {
+                    if lengths.any(|l| l != first) {
+                        return Err(parse_error());
+                    }
+                }
+                return Ok(Some(Request {
+                    request_line,
+                    headers: std::mem::take(&mut self.headers).into(),
+                    body: None,
+                    connection: None,
+                }));
+            }
+        }
+    }
+}
+
+/// A request head that did not come as HTTP/1.1 text, an HTTP/2 stream's
+/// say, put through the same parser and limits. Header names may be in any
+/// case. Headers the parser has no use for are left out rather than
+/// rejected, as such clients send plenty of them.
+pub fn request_from_parts<'a, I>(
+    method: &str,
+    target: &str,
+    headers: I,
+    config: ServerConfig,
+) -> Result<Request>
+where
+    I: IntoIterator<Item = (&'a str, &'a [u8])>,
+{
+    let mut head = BytesMut::new();
+    head.extend_from_slice(format!("{} {} HTTP/1.1\r\n", method, target).as_bytes());
+    for (name, value) in headers {
+        let mut line = name.as_bytes().to_vec();
+        line.extend_from_slice(b": ");
+        line.extend_from_slice(value);
+        line.extend_from_slice(b"\r\n");
+        if value.contains(&b'\n') || parse_header(&line).is_err() {
+            continue;
+        }
+        head.extend_from_slice(&line);
+    }
+    head.extend_from_slice(b"\r\n");
+    RequestParser::new(config)
+        .parse(&mut head)?
+        .ok_or_else(parse_error)
+}
+
+fn parse_error() -> Error {
+    Error::Rejected(StatusCode::SC400)
+}
+
+/// Parses a complete request; everything after the head is the body.
+pub fn parse_request(input: &[u8]) -> Result<Request> {
+    let mut buf = BytesMut::from(input);
+    match RequestParser::default().parse(&mut buf)? {
+        Some(mut request) => {
+            request.body = Some(RequestBody::Full(buf.freeze()));
+            Ok(request)
+        }
+        None => Err(parse_error()),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use nom::character::complete::crlf;
+    use nom::multi::many0;
 
     use super::*;
 
+    fn parse_headers(input: &[u8]) -> IResult<&[u8], Vec<Header>> {
+        let (input, headers) = many0(parse_header).parse(input)?;
+        Ok((input, headers.into_iter().flatten().collect()))
+    }
+
     #[test]
     fn test_decode_request_get() -> crate::Result<()> {
         let req = b"GET /index.html HTTP/1.1\r\nHost: localhost:4221\r\nUser-Agent: curl/7.64.1\r\nAccept: */*\r\n\r\n";
@@ -153,6 +315,7 @@ mod tests {
                 request_line,
                 headers: headers.into(),
                 body: None,
+                connection: None,
             },
         )
         .parse(req);
@@ -168,6 +331,7 @@ mod tests {
                 request_line,
                 headers: headers.into(),
                 body: None,
+                connection: None,
             },
         )
         .parse(req);
@@ -183,6 +347,7 @@ mod tests {
                 request_line,
                 headers: headers.into(),
                 body: None,
+                connection: None,
             },
         )
         .parse(req);
@@ -191,4 +356,174 @@ mod tests {
 
         Ok(())
     }
+    #[test]
+    fn test_conditional_headers() -> Result<()> {
+        let req = b"GET /files/foo HTTP/1.1\r\nHost: localhost:4221\r\nIf-None-Match: \"a\", W/\"b\"\r\nIf-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\nIf-Range: not a date\r\nIf-Match: nonsense\r\n\r\n";
+        let request = parse_request(req)?;
+        assert_eq!(
+            request.headers.if_none_match(),
+            Some(EntityTags::Tags(vec![
+                crate::ETag::strong("a"),
+                crate::ETag::weak("b")
+            ]))
+        );
+        assert_eq!(
+            request.headers.if_modified_since().map(|d| d.timestamp()),
+            Some(784111777)
+        );
+        assert_eq!(request.headers.if_range(), None);
+        assert_eq!(request.headers.if_match(), None);
+        let request = parse_request(b"GET / HTTP/1.1\r\nIf-Range: \"abc\"\r\n\r\n")?;
+        assert_eq!(
+            request.headers.if_range(),
+            Some(IfRange::ETag(crate::ETag::strong("abc")))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_names_in_any_case() -> Result<()> {
+        let req = b"GET / HTTP/1.1\r\nhost: localhost\r\nLAST-EVENT-ID: 7\r\nsec-websocket-key: k\r\ncontent-length: 0\r\n\r\n";
+        let request = parse_request(req)?;
+        assert_eq!(
+            request.headers.host().as_deref().map(|h| h.as_str()),
+            Some("localhost")
+        );
+        assert_eq!(
+            request.headers.last_event_id().map(|id| id.0),
+            Some("7".to_string())
+        );
+        assert_eq!(
+            request.headers.sec_websocket_key().map(|k| k.0),
+            Some("k".to_string())
+        );
+        assert_eq!(request.headers.content_length().map(|l| *l), Some(0));
+        // As HTTP/2 sends them.
+        let headers = [
+            ("last-event-id", &b"8"[..]),
+            ("sec-websocket-version", b"13"),
+        ];
+        let request = request_from_parts("GET", "/", headers, Default::default())?;
+        assert_eq!(
+            request.headers.last_event_id().map(|id| id.0),
+            Some("8".to_string())
+        );
+        assert_eq!(
+            request.headers.sec_websocket_version().map(|v| v.0),
+            Some("13".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_headers() -> Result<()> {
+        let req = b"GET / HTTP/1.1\r\nCache-Control: no-cache\r\nOrigin: http://a\r\nContent-Type: text/plain; charset=utf-8\r\nX-Empty:\r\nHost: a\r\n\r\n";
+        let request = parse_request(req)?;
+        assert_eq!(request.headers.iter().count(), 1);
+        // A header we know does not turn unknown for a bad value.
+        assert!(parse_request(b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n").is_err());
+        assert!(parse_request(b"GET / HTTP/1.1\r\nNo Name\r\n\r\n").is_err());
+        assert!(parse_request(b"GET / HTTP/1.1\r\nBad Name: x\r\n\r\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_body_framing() -> Result<()> {
+        let length = |head: &[u8]| parse_request(head).map(|r| r.headers.content_length());
+        // Whatever the whitespace around the value, the length is seen.
+        let lengths = [
+            &b"POST / HTTP/1.1\r\nContent-Length:5\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\ncontent-length: \t5 \r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n",
+        ];
+        for head in lengths {
+            assert_eq!(length(head)?.map(|l| *l), Some(5));
+        }
+        let malformed = [
+            &b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\n",
+        ];
+        for head in malformed {
+            assert!(matches!(
+                length(head),
+                Err(Error::Rejected(StatusCode::SC400))
+            ));
+        }
+        let chunked = [
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\ntransfer-encoding:chunked\r\nContent-Length: 5\r\n\r\n",
+        ];
+        for head in chunked {
+            assert!(matches!(
+                length(head),
+                Err(Error::Rejected(StatusCode::SC501))
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_byte_by_byte() -> Result<()> {
+        let req = b"GET /echo/abc HTTP/1.1\r\nHost: localhost:4221\r\nContent-Length: 3\r\n\r\nabc";
+        let head_len = req.len() - 3;
+        let mut parser = RequestParser::default();
+        let mut buf = BytesMut::new();
+        for (i, b) in req[..head_len].iter().enumerate() {
+            buf.extend_from_slice(&[*b]);
+            let parsed = parser.parse(&mut buf)?;
+            assert_eq!(parsed.is_some(), i == head_len - 1);
+            if let Some(request) = parsed {
+                assert_eq!(request.get_route(), "/echo");
+                assert_eq!(request.headers.content_length().map(|l| *l), Some(3));
+            }
+        }
+        assert!(buf.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_errors_and_pipelining() -> Result<()> {
+        let mut buf = BytesMut::from(&b"BREW /pot HTTP/1.1\r\n"[..]);
+        assert!(RequestParser::default().parse(&mut buf).is_err());
+        // Malformed, however little of it there is yet.
+        let mut buf = BytesMut::from(&b"GET /\r\n"[..]);
+        assert!(RequestParser::default().parse(&mut buf).is_err());
+
+        let mut buf = BytesMut::from(&b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET"[..]);
+        let mut parser = RequestParser::default();
+        let first = parser.parse(&mut buf)?.map(|r| r.get_route());
+        let second = parser.parse(&mut buf)?.map(|r| r.get_route());
+        assert_eq!(first, Some("/a".to_string()));
+        assert_eq!(second, Some("/b".to_string()));
+        assert!(parser.parse(&mut buf)?.is_none());
+        assert_eq!(&buf[..], b"GET");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_limits() {
+        let config = ServerConfig {
+            max_request_line: 32,
+            max_headers: 2,
+            max_header_bytes: 64,
+            ..Default::default()
+        };
+        let rejected = |input: &[u8]| {
+            let mut buf = BytesMut::from(input);
+            match RequestParser::new(config).parse(&mut buf) {
+                Err(Error::Rejected(code)) => Some(code),
+                _ => None,
+            }
+        };
+        let long_target = format!("GET /{} HTTP/1.1\r\n", "a".repeat(40));
+        assert_eq!(rejected(long_target.as_bytes()), Some(StatusCode::SC414));
+        // Without a line end yet, but already too long.
+        assert_eq!(rejected(&[b'G'; 33]), Some(StatusCode::SC414));
+        let many = b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\nHost: c\r\n\r\n";
+        assert_eq!(rejected(many), Some(StatusCode::SC431));
+        let big = format!("GET / HTTP/1.1\r\nUser-Agent: {}", "a".repeat(60));
+        assert_eq!(rejected(big.as_bytes()), Some(StatusCode::SC431));
+        assert_eq!(rejected(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"), None);
+    }
 }
@@ -1,11 +1,20 @@
 use crate::{
-    parse_request, Connection, Context, Headers, HttpMethod, HttpVersion, Result, UserAgent,
+    Connection, ConnectionInfo, ContentLength, Context, Error, Expect, Headers, HttpMethod,
+    HttpVersion, RequestParser, Result, ServerConfig, StatusCode, UserAgent,
 };
 use bytes::{Bytes, BytesMut};
-use derive_more::{Deref, From, Into};
+use derive_more::{Deref, From};
 use regex::Regex;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{timeout_at, Instant};
+
+/// Bodies up to this size are read before routing; larger ones are left on
+/// the wire for a `BodyHandler` to consume.
+pub const MAX_BUFFERED_BODY: u64 = 1024 * 1024;
+const BODY_CHUNK: usize = 64 * 1024;
+const HEAD_CHUNK: usize = 4 * 1024;
 
 #[derive(Debug, Clone, PartialEq, From, Deref)]
 pub struct RequestTarget(pub String);
@@ -14,18 +23,131 @@ impl RequestTarget {
     pub fn start_with(&self, prefix: &str) -> bool {
         self.0.starts_with(prefix)
     }
+    /// The target in authority form, as CONNECT sends it: `host:port`,
+    /// with an IPv6 address in brackets. The port can not be left out.
+    pub fn authority(&self) -> Option<Authority> {
+        let (host, port) = self.0.rsplit_once(':')?;
+        let port = port.parse().ok().filter(|port| *port != 0)?;
+        let host = match host.strip_prefix('[') {
+            Some(v6) => v6.strip_suffix(']')?.parse::<Ipv6Addr>().ok()?.to_string(),
+            None => {
+                let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '.');
+                Some(host.to_string()).filter(|h| !h.is_empty() && h.chars().all(valid))?
+            }
+        };
+        Some(Authority { host, port })
+    }
+}
+
+/// Where a tunnel goes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Authority {
+    pub host: String,
+    pub port: u16,
 }
 #[derive(Debug, Clone, PartialEq)]
 pub struct RequestLine(pub HttpMethod, pub RequestTarget, pub HttpVersion);
 
-#[derive(Debug, Clone, From, Deref, Into)]
-pub struct RequestBody(pub Bytes);
+#[derive(Debug, Clone)]
+pub enum RequestBody {
+    Full(Bytes),
+    /// Not read yet; only a `BodyReader` gets to it.
+    Stream(ContentLength),
+}
+
+impl RequestBody {
+    pub fn bytes(&self) -> Option<Bytes> {
+        match self {
+            RequestBody::Full(b) => Some(b.clone()),
+            RequestBody::Stream(_) => None,
+        }
+    }
+    pub fn len(&self) -> u64 {
+        match self {
+            RequestBody::Full(b) => b.len() as u64,
+            RequestBody::Stream(len) => **len,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Hands out a request body chunk by chunk, so it never has to be in memory
+/// at once. `remaining` and `buffered` belong to the connection: whatever the
+/// reader leaves behind is what the connection still has to skip, and bytes
+/// read past the body stay buffered for the next request.
+pub struct BodyReader<'a> {
+    stream: &'a mut (dyn AsyncRead + Unpin + Send),
+    buffered: &'a mut BytesMut,
+    remaining: &'a mut u64,
+    timeout: Option<Duration>,
+}
+
+impl<'a> BodyReader<'a> {
+    pub fn new(
+        stream: &'a mut (dyn AsyncRead + Unpin + Send),
+        buffered: &'a mut BytesMut,
+        remaining: &'a mut u64,
+    ) -> Self {
+        BodyReader {
+            stream,
+            buffered,
+            remaining,
+            timeout: None,
+        }
+    }
+    /// Gives up with 408 when the client sends nothing for `timeout`.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        BodyReader {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+    pub fn remaining(&self) -> u64 {
+        *self.remaining
+    }
+    /// The next piece of the body, `None` once all of it was read.
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>> {
+        if *self.remaining == 0 {
+            return Ok(None);
+        }
+        if self.buffered.is_empty() {
+            self.buffered.reserve(BODY_CHUNK);
+            let read = self.stream.read_buf(self.buffered);
+            let n = match self.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, read)
+                    .await
+                    .map_err(|_| Error::Rejected(StatusCode::SC408))?,
+                None => read.await,
+            }
+            .context("Read request body")?;
+            if n == 0 {
+                return Err(Error::GeneralError(
+                    "Connection closed before the end of the body".to_string(),
+                ));
+            }
+        }
+        let n = (*self.remaining).min(self.buffered.len() as u64);
+        *self.remaining -= n;
+        Ok(Some(self.buffered.split_to(n as usize).freeze()))
+    }
+    pub async fn to_bytes(mut self) -> Result<Bytes> {
+        let mut body = BytesMut::new();
+        while let Some(chunk) = self.chunk().await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body.freeze())
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Request {
     pub request_line: RequestLine,
     pub headers: Headers,
     pub body: Option<RequestBody>,
+    /// Set by the server; requests made up elsewhere come from nowhere.
+    pub connection: Option<ConnectionInfo>,
 }
 
 impl Request
@@ -44,10 +166,22 @@ where
     pub fn connection(&self) -> Option<Connection> {
         self.headers.connection()
     }
+    pub fn http_version(&self) -> HttpVersion {
+        self.request_line.2
+    }
+    /// Whether interim responses may be sent ahead of the final one.
+    pub fn takes_interim(&self) -> bool {
+        self.http_version() != HttpVersion::HttpOneZero
+    }
+    /// The client waits for the route to take the body before sending it.
+    /// An HTTP/1.0 client can not be asked to, the expectation is ignored.
+    pub fn expects_continue(&self) -> bool {
+        self.headers.expect() == Some(Expect::Continue) && self.takes_interim()
+    }
 
     fn split_target(&self) -> (String, String) {
         let p = self.target().0.clone();
-        let req_path = Regex::new(r"^(?<route>/[^/]+)((?:/)(?<path>[^/]+))?$").unwrap();
+        let req_path = Regex::new(r"^(?<route>/[^/]+)((?:/)(?<path>.*))?$").unwrap();
         //   let req_path = Regex::new(r"^(?<route>/[^/]+)(?:/)(?<path>[^/]+)$").unwrap();
 
         match req_path.captures(p.as_str()) {
@@ -73,23 +207,153 @@ where
     pub fn headers(&self) -> &Headers {
         &self.headers
     }
-    pub async fn read(stream: &mut TcpStream) -> Result<Self> {
-        let input = Self::read_request(stream).await.with_context(|| "");
-        parse_request(&input?)
+    pub fn connection_info(&self) -> Option<ConnectionInfo> {
+        self.connection.clone()
     }
-    async fn read_request(stream: &mut TcpStream) -> std::io::Result<Bytes> {
-        let mut req = BytesMut::new();
-        let mut buffer = [0; 1024];
-        loop {
-            let n = stream.read(&mut buffer).await?;
-            if n == 0 {
-                break; // EOF
+    /// Reads the next request off the connection. `buffered` carries bytes
+    /// read past the end of one request over to the next.
+    pub async fn read<S>(
+        stream: &mut S,
+        buffered: &mut BytesMut,
+        config: &ServerConfig,
+    ) -> Result<Self>
+    where
+        S: AsyncRead + Unpin + Send,
+    {
+        let mut parser = RequestParser::new(*config);
+        // The head has to be complete `header_timeout` after its first byte.
+        // Until that arrives the connection is idle.
+        let mut started = (!buffered.is_empty()).then(Instant::now);
+        let mut request = loop {
+            if let Some(request) = parser.parse(buffered)? {
+                break request;
             }
-            req.extend_from_slice(&buffer[..n]);
-            if req.windows(4).any(|w| w == b"\r\n\r\n") {
-                break;
+            let deadline = match started {
+                Some(started) => started + config.header_timeout,
+                None => Instant::now() + config.idle_timeout,
+            };
+            buffered.reserve(HEAD_CHUNK);
+            let n = match timeout_at(deadline, stream.read_buf(buffered)).await {
+                Ok(n) => n.context("Read request")?,
+                Err(_) if started.is_some() => return Err(Error::Rejected(StatusCode::SC408)),
+                Err(_) => return Err(Error::GeneralError("Idle connection timed out".to_string())),
+            };
+            if n == 0 {
+                return Err(Error::GeneralError(
+                    "Connection closed before a complete request".to_string(),
+                ));
             }
+            started.get_or_insert_with(Instant::now);
+        };
+        // HTTP/1.1 makes it mandatory, one without it is malformed.
+        if request.http_version() == HttpVersion::HttpOne && request.headers.host().is_none() {
+            return Err(Error::Rejected(StatusCode::SC400));
+        }
+        let len = request.headers.content_length().map_or(0, |l| *l);
+        if len > config.max_body {
+            return Err(Error::Rejected(StatusCode::SC413));
         }
-        Ok(req.freeze())
+        // A client with an expectation holds the body back until it is met.
+        let held_back = request.expects_continue()
+            || matches!(request.headers.expect(), Some(Expect::Other(_)));
+        request.body = Some(if len > MAX_BUFFERED_BODY || (held_back && len > 0) {
+            RequestBody::Stream(len.into())
+        } else {
+            let mut remaining = len;
+            let body =
+                BodyReader::new(stream, buffered, &mut remaining).with_timeout(config.body_timeout);
+            RequestBody::Full(body.to_bytes().await?)
+        });
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    // A connected pair: the client end and the server end.
+    async fn connection() -> Result<(TcpStream, TcpStream)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.context("bind")?;
+        let addr = listener.local_addr().context("addr")?;
+        let client = TcpStream::connect(addr).await.context("connect")?;
+        let (server, _) = listener.accept().await.context("accept")?;
+        Ok((client, server))
+    }
+
+    #[test]
+    fn test_authority() {
+        let authority = |target: &str| RequestTarget(target.to_string()).authority();
+        assert_eq!(
+            authority("example.com:443"),
+            Some(Authority {
+                host: "example.com".to_string(),
+                port: 443
+            })
+        );
+        assert_eq!(
+            authority("[::1]:8443").map(|a| a.host),
+            Some("::1".to_string())
+        );
+        assert_eq!(authority("example.com"), None);
+        assert_eq!(authority("example.com:0"), None);
+        assert_eq!(authority("::1:443"), None);
+        assert_eq!(authority("/index.html"), None);
+        assert_eq!(authority("user@example.com:443"), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_timeouts() -> Result<()> {
+        let config = ServerConfig {
+            header_timeout: Duration::from_millis(50),
+            body_timeout: Duration::from_millis(50),
+            idle_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let read = |mut server: TcpStream| async move {
+            Request::read(&mut server, &mut BytesMut::new(), &config).await
+        };
+
+        let (_client, server) = connection().await?;
+        let idle = read(server).await;
+        assert!(matches!(idle, Err(Error::GeneralError(_))));
+
+        let (mut client, server) = connection().await?;
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nHo")
+            .await
+            .context("write")?;
+        let trickled = read(server).await;
+        assert!(matches!(trickled, Err(Error::Rejected(StatusCode::SC408))));
+
+        let (mut client, server) = connection().await?;
+        let head = b"PUT /files/a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nabc";
+        client.write_all(head).await.context("write")?;
+        let stalled = read(server).await;
+        assert!(matches!(stalled, Err(Error::Rejected(StatusCode::SC408))));
+
+        let (mut client, server) = connection().await?;
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .context("write")?;
+        assert_eq!(read(server).await?.get_route(), "/");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_missing_host() -> Result<()> {
+        let read = |head: &'static [u8]| async move {
+            let (mut client, mut server) = connection().await?;
+            client.write_all(head).await.context("write")?;
+            Request::read(&mut server, &mut BytesMut::new(), &Default::default()).await
+        };
+        let missing = read(b"GET / HTTP/1.1\r\n\r\n").await;
+        assert!(matches!(missing, Err(Error::Rejected(StatusCode::SC400))));
+        // HTTP/1.0 has no such rule.
+        assert_eq!(read(b"GET / HTTP/1.0\r\n\r\n").await?.get_route(), "/");
+        Ok(())
     }
 }
@@ -0,0 +1,182 @@
+use crate::{Context, Error, FileBody, Result};
+#[cfg(target_os = "linux")]
+use std::any::Any;
+use std::future::Future;
+use std::os::unix::fs::FileExt;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+#[cfg(target_os = "linux")]
+use tokio::net::{TcpStream, UnixStream};
+
+// sendfile(2) moves at most this much per call.
+#[cfg(target_os = "linux")]
+const MAX_SENDFILE: i64 = 0x7fff_f000;
+const COPY_CHUNK: usize = 64 * 1024;
+
+/// Sends a file body. A TCP or Unix socket gets it straight from the page
+/// cache with `sendfile(2)`; other streams, which may encrypt or frame what they carry,
+/// get it copied through a buffer. A client that takes nothing for `stall`
+/// ends it, however long the whole file takes.
+pub async fn send_file<S>(stream: &mut S, body: &FileBody, stall: Duration) -> Result<()>
+where
+    S: AsyncWrite + Unpin + 'static,
+{
+    #[cfg(target_os = "linux")]
+    if let Some(tcp) = (stream as &mut dyn Any).downcast_mut::<TcpStream>() {
+        return sendfile(tcp, body, stall).await;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(unix) = (stream as &mut dyn Any).downcast_mut::<UnixStream>() {
+        return sendfile(unix, body, stall).await;
+    }
+    copy_file(stream, body, stall).await
+}
+
+/// One write of a response, which has `stall` to get anywhere. Bounding
+/// each write instead of the whole response lets a slow client that keeps
+/// reading take as long as a large body needs.
+pub(crate) async fn progress<T, F>(stall: Duration, what: &'static str, write: F) -> Result<T>
+where
+    F: Future<Output = std::io::Result<T>>,
+{
+    tokio::time::timeout(stall, write)
+        .await
+        .map_err(|_| Error::GeneralError(format!("{what} timed out")))?
+        .context(what)
+}
+
+// The readiness calls `sendfile` needs, which tokio's sockets each have but
+// share no trait for.
+#[cfg(target_os = "linux")]
+trait Socket: std::os::fd::AsRawFd {
+    async fn ready(&self) -> std::io::Result<()>;
+    fn try_send(&self, f: impl FnOnce() -> std::io::Result<isize>) -> std::io::Result<isize>;
+}
+
+#[cfg(target_os = "linux")]
+macro_rules! impl_socket {
+    ($($t:ty),*) => {$(
+        impl Socket for $t {
+            async fn ready(&self) -> std::io::Result<()> {
+                self.writable().await
+            }
+            fn try_send(
+                &self,
+                f: impl FnOnce() -> std::io::Result<isize>,
+            ) -> std::io::Result<isize> {
+                self.try_io(tokio::io::Interest::WRITABLE, f)
+            }
+        }
+    )*};
+}
+
+#[cfg(target_os = "linux")]
+impl_socket!(TcpStream, UnixStream);
+
+#[cfg(target_os = "linux")]
+async fn sendfile<S: Socket>(stream: &mut S, body: &FileBody, stall: Duration) -> Result<()> {
+    use std::io::ErrorKind;
+    use std::os::fd::AsRawFd;
+
+    let (socket, file) = (stream.as_raw_fd(), body.file().as_raw_fd());
+    let len = body.len() as libc::off_t;
+    let mut offset: libc::off_t = 0;
+    while offset < len {
+        progress(stall, "Wait for socket", stream.ready()).await?;
+        let count = (len - offset).min(MAX_SENDFILE) as usize;
+        let sent = stream.try_send(|| {
+            // SAFETY: both descriptors stay open for the call, `offset` is a
+            // valid pointer and the kernel only advances it.
+            match unsafe { libc::sendfile(socket, file, &mut offset, count) } {
+                n if n < 0 => Err(std::io::Error::last_os_error()),
+                n => Ok(n),
+            }
+        });
+        match sent {
+            Ok(0) => return Err(shrank()),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e).context("sendfile"),
+        }
+    }
+    Ok(())
+}
+
+async fn copy_file<S>(stream: &mut S, body: &FileBody, stall: Duration) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; COPY_CHUNK.min(body.len() as usize)];
+    let mut offset = 0;
+    while offset < body.len() {
+        let want = (body.len() - offset).min(buf.len() as u64) as usize;
+        let n = body
+            .file()
+            .read_at(&mut buf[..want], offset)
+            .context("Reading file")?;
+        if n == 0 {
+            return Err(shrank());
+        }
+        progress(stall, "Write file", stream.write_all(&buf[..n])).await?;
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+fn shrank() -> Error {
+    Error::GeneralError("File shrank while it was being sent".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileOps;
+    use bytes::Bytes;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_send_file() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("send-file-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        // Larger than the socket buffers, so the writer has to wait for room.
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        path.write(Bytes::from(data.clone()))?;
+        let (body, _) = path.open()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await.context("bind")?;
+        let addr = listener.local_addr().context("addr")?;
+        let reader = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.context("accept")?;
+            let mut received = vec![];
+            stream.read_to_end(&mut received).await.context("read")?;
+            Ok::<_, Error>(received)
+        });
+        let mut stream = TcpStream::connect(addr).await.context("connect")?;
+        send_file(&mut stream, &body, Duration::from_secs(5)).await?;
+        drop(stream);
+        let received = reader.await.context("join")??;
+        assert!(received == data);
+
+        let (mut writer, mut reader) = tokio::net::UnixStream::pair().context("pair")?;
+        let reader = tokio::spawn(async move {
+            let mut received = vec![];
+            reader.read_to_end(&mut received).await.context("read")?;
+            Ok::<_, Error>(received)
+        });
+        send_file(&mut writer, &body, Duration::from_secs(5)).await?;
+        drop(writer);
+        assert!(reader.await.context("join")?? == data);
+
+        let (mut writer, mut reader) = tokio::io::duplex(64 * 1024);
+        let reader = tokio::spawn(async move {
+            let mut received = vec![];
+            reader.read_to_end(&mut received).await.context("read")?;
+            Ok::<_, Error>(received)
+        });
+        send_file(&mut writer, &body, Duration::from_secs(5)).await?;
+        drop(writer);
+        assert!(reader.await.context("join")?? == data);
+        std::fs::remove_file(path).context("cleanup")?;
+        Ok(())
+    }
+}
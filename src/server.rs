@@ -1,13 +1,114 @@
+use std::fs::Permissions;
 use std::future::Future;
+use std::io::IoSlice;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, ToSocketAddrs};
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs, UnixListener, UnixStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
 
-use crate::{Complete, Connection, Context, Error, Request, Result, State};
+use crate::{
+    finished, h2c_preface, mk_response, negotiated, progress, reject, send_file, serve_h2,
+    starts_with_preface, wants_h2c, BodyReader, BodyStream, Complete, Connection, ConnectionCounts,
+    ConnectionInfo, ConnectionSlot, Context, Deferred, Error, Expect, Header, Overload,
+    PeerCredentials, Request, RequestBody, Response, ResponseBody, Result, Rewind, State,
+    StatusCode, StatusLine, TlsConfig, TlsReloader, TransferEncoding, Upgraded, Upgrading,
+    MAX_BUFFERED_BODY,
+};
+
+/// Limits on what a client may send. Requests over them are answered with
+/// the status given for each and the connection is closed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServerConfig {
+    /// Longest request line, CRLF included: 414.
+    pub max_request_line: usize,
+    /// Most header fields in one request: 431.
+    pub max_headers: usize,
+    /// Most bytes in all header lines together: 431.
+    pub max_header_bytes: usize,
+    /// Largest `Content-Length`: 413. Routes can lower it with `max_body`.
+    pub max_body: u64,
+    /// Time from the first byte of a request to the end of its head: 408.
+    /// Also bounds a TLS handshake.
+    pub header_timeout: Duration,
+    /// Longest pause between two reads of a body: 408.
+    pub body_timeout: Duration,
+    /// How long a kept-alive connection may wait for its next request.
+    pub idle_timeout: Duration,
+    /// Longest a write of a response may wait for the client to take any of
+    /// it; after it the connection is dropped, as the status line is already
+    /// out. A client that keeps reading may take as long as the body needs.
+    pub write_timeout: Duration,
+    /// How long a shutdown waits for open connections before cutting them.
+    pub drain_timeout: Duration,
+    /// Most connections open at once; `overload` says what happens past it.
+    /// The default fits the process's file descriptor limit.
+    pub max_connections: usize,
+    /// Most connections open at once from one address. Past it, connections
+    /// are answered 503 whatever `overload` says, TLS ones closed.
+    pub max_connections_per_ip: Option<usize>,
+    pub overload: Overload,
+    /// Sent with every 503 for too many connections.
+    pub retry_after: Duration,
+    /// How often a TLS listener looks for changed certificate files.
+    pub cert_poll: Duration,
+    /// Most HTTP/2 streams a client may have open on one connection.
+    pub max_concurrent_streams: u32,
+}
+
+// Pause after a failed accept, for descriptors to be freed.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// Descriptors kept for the listener, certificates and the like.
+const RESERVED_FDS: u64 = 64;
+
+/// As many connections as the soft `RLIMIT_NOFILE` allows, counting two
+/// descriptors each: the socket and a file being sent.
+fn fd_connection_limit() -> usize {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let fds = match unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } {
+        0 => limit.rlim_cur,
+        _ => 1024,
+    };
+    (fds.saturating_sub(RESERVED_FDS) / 2).clamp(1, 10_000) as usize
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_bytes: 64 * 1024,
+            max_body: 1024 * 1024 * 1024,
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(60),
+            write_timeout: Duration::from_secs(30),
+            drain_timeout: Duration::from_secs(30),
+            max_connections: fd_connection_limit(),
+            max_connections_per_ip: None,
+            overload: Overload::PauseAccept,
+            retry_after: Duration::from_secs(1),
+            cert_poll: Duration::from_secs(10),
+            max_concurrent_streams: 100,
+        }
+    }
+}
 
 pub struct Server {
-    listener: TcpListener,
+    listener: Listener,
+    config: ServerConfig,
+    counts: ConnectionCounts,
 }
 
 impl Server {
@@ -15,46 +116,1058 @@ impl Server {
         let listener = TcpListener::bind(addr)
             .await
             .with_context(|| "bind connection")?;
-        Ok(Server { listener })
+        Ok(Server::new(Listener::Tcp(listener)))
+    }
+    /// Listens for TLS connections. The certificate files are read now, a
+    /// problem with them is an error here rather than at the first
+    /// handshake. While serving they are read again on SIGHUP or when they
+    /// change.
+    pub async fn bind_tls<A: ToSocketAddrs>(addr: A, config: TlsConfig) -> Result<Server> {
+        let reloader = TlsReloader::new(config)?;
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| "bind connection")?;
+        Ok(Server::new(Listener::Tls { listener, reloader }))
+    }
+    /// Listens on a Unix socket at `path`, open to its owner and group.
+    pub async fn bind_unix<P: AsRef<Path>>(path: P) -> Result<Server> {
+        Server::bind_unix_with_mode(path, 0o660).await
+    }
+    /// Listens on a Unix socket at `path` with the given permissions. A
+    /// socket left there by a server that is gone is replaced; one that is
+    /// still answering is not. The socket file is removed again when the
+    /// server shuts down or is dropped.
+    pub async fn bind_unix_with_mode<P: AsRef<Path>>(path: P, mode: u32) -> Result<Server> {
+        let path = path.as_ref();
+        remove_stale_socket(path).await?;
+        let listener = UnixListener::bind(path).with_context(|| "bind unix socket")?;
+        let file = SocketFile::new(path)?;
+        std::fs::set_permissions(path, Permissions::from_mode(mode))
+            .context("Socket permissions")?;
+        Ok(Server::new(Listener::Unix {
+            listener,
+            _file: file,
+        }))
+    }
+    fn new(listener: Listener) -> Server {
+        let config = ServerConfig::default();
+        Server {
+            listener,
+            config,
+            counts: ConnectionCounts::new(config.max_connections, config.max_connections_per_ip),
+        }
+    }
+    /// Starts counting afresh, take `connection_counts` after this.
+    pub fn with_config(self, config: ServerConfig) -> Self {
+        let counts = ConnectionCounts::new(config.max_connections, config.max_connections_per_ip);
+        Server {
+            config,
+            counts,
+            ..self
+        }
+    }
+    pub fn connection_counts(&self) -> ConnectionCounts {
+        self.counts.clone()
+    }
+    /// For reloading the certificates of a TLS listener by hand.
+    pub fn tls_reloader(&self) -> Option<TlsReloader> {
+        match &self.listener {
+            Listener::Tls { reloader, .. } => Some(reloader.clone()),
+            _ => None,
+        }
+    }
+    /// Errors for a Unix socket, which has a path instead.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(listener) | Listener::Tls { listener, .. } => {
+                listener.local_addr().context("Local address")
+            }
+            Listener::Unix { .. } => Err(Error::GeneralError("Not a TCP listener".to_string())),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Tls {
+        listener: TcpListener,
+        reloader: TlsReloader,
+    },
+    // The file goes when the listener does.
+    Unix {
+        listener: UnixListener,
+        _file: SocketFile,
+    },
+}
+
+enum Accepted {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    // The handshake is left to the connection's task, so a slow client
+    // cannot hold up the accept loop.
+    Tls(TcpStream, TlsAcceptor),
+}
+
+impl Listener {
+    async fn accept(&self) -> std::io::Result<(Accepted, ConnectionInfo)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                let local = stream.local_addr().ok();
+                let info = ConnectionInfo::new(Some(peer), local);
+                Ok((Accepted::Tcp(stream), info))
+            }
+            Listener::Tls { listener, reloader } => {
+                let (stream, peer) = listener.accept().await?;
+                let local = stream.local_addr().ok();
+                let info = ConnectionInfo::new(Some(peer), local);
+                Ok((Accepted::Tls(stream, reloader.acceptor()), info))
+            }
+            Listener::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                let peer_credentials = stream.peer_cred().ok().map(|cred| PeerCredentials {
+                    uid: cred.uid(),
+                    gid: cred.gid(),
+                    pid: cred.pid(),
+                });
+                let info = ConnectionInfo {
+                    peer_credentials,
+                    ..ConnectionInfo::new(None, None)
+                };
+                Ok((Accepted::Unix(stream), info))
+            }
+        }
+    }
+}
+
+// The socket file of a Unix listener, removed on drop unless another server
+// has put its own in its place since.
+struct SocketFile {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+}
+
+impl SocketFile {
+    fn new(path: &Path) -> Result<SocketFile> {
+        let meta = std::fs::symlink_metadata(path).context("Socket file")?;
+        Ok(SocketFile {
+            path: path.to_path_buf(),
+            dev: meta.dev(),
+            ino: meta.ino(),
+        })
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Ok(meta) = std::fs::symlink_metadata(&self.path) {
+            if (meta.dev(), meta.ino()) == (self.dev, self.ino) {
+                let _ = std::fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+// Binding fails on an existing file, so a socket whose server is gone has to
+// be removed first. Anything else at `path` is left alone.
+async fn remove_stale_socket(path: &Path) -> Result<()> {
+    let Ok(meta) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !meta.file_type().is_socket() {
+        return Err(Error::GeneralError(format!(
+            "{} exists and is not a socket",
+            path.display()
+        )));
     }
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(Error::GeneralError(format!(
+            "{} is in use by a running server",
+            path.display()
+        )));
+    }
+    std::fs::remove_file(path).context("Remove stale socket")
 }
 
+#[allow(async_fn_in_trait)]
 pub trait Serve {
     async fn serve<F, Fut>(self, f: Arc<F>) -> Result<()>
     where
         F: Fn(State) -> Fut + Send + Sync + 'static,
-        Fut: Future<Output = Result<State>> + Send;
+        Fut: Future<Output = Result<State>> + Send + 'static;
+    /// Serves until `signal` completes, then stops accepting and lets the
+    /// open connections finish their current request, for at most
+    /// `drain_timeout`. Kept-alive connections are told `Connection: close`.
+    async fn serve_with_shutdown<F, Fut, S>(self, f: Arc<F>, signal: S) -> Result<()>
+    where
+        F: Fn(State) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<State>> + Send + 'static,
+        S: Future<Output = ()>;
 }
 
 impl Serve for Server {
     async fn serve<F, Fut>(self, f: Arc<F>) -> Result<()>
     where
         F: Fn(State) -> Fut + Send + Sync + 'static,
-        Fut: Future<Output = Result<State>> + Send,
+        Fut: Future<Output = Result<State>> + Send + 'static,
+    {
+        self.serve_with_shutdown(f, std::future::pending()).await
+    }
+
+    async fn serve_with_shutdown<F, Fut, S>(self, f: Arc<F>, signal: S) -> Result<()>
+    where
+        F: Fn(State) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<State>> + Send + 'static,
+        S: Future<Output = ()>,
     {
+        let (drain, draining) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        let mut lingering = JoinSet::new();
+        let pause = self.config.overload == Overload::PauseAccept;
+        let mut reserved = None;
+        let watching = self
+            .tls_reloader()
+            .map(|reloader| tokio::spawn(reloader.watch(self.config.cert_poll)));
+        tokio::pin!(signal);
         loop {
-            let (mut stream, _) = self.listener.accept().await.with_context(|| "")?;
-            let f_cloned = Arc::clone(&f);
-            tokio::spawn(async move {
-                loop {
-                    let request = Request::read(&mut stream).await?;
-                    let state = f_cloned(State::incomplete(Arc::new(request))).await?;
-                    match state {
-                        State::Incomplete(_) => {}
-                        State::Complete(Complete(req, resp)) => {
-                            let bytes: Vec<u8> = {
-                                resp.borrow().clone().into()
-                            };
-                            stream.write_all(bytes.as_ref()).await.with_context(|| "")?;
-                            stream.flush().await.with_context(|| "flushing ")?;
-                            if req.headers.connection() == Some(Connection::Close) {
-                                break;
-                            }
+            tokio::select! {
+                permit = self.counts.reserve(), if pause && reserved.is_none() => {
+                    reserved = permit;
+                }
+                accepted = self.listener.accept(), if !pause || reserved.is_some() => {
+                    // Out of descriptors or a connection gone before it was
+                    // taken: neither is a reason to stop serving.
+                    let (stream, info) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            eprintln!("Accepting a connection failed: {e:?}");
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    let ip = info.remote_addr.map(|addr| addr.ip());
+                    let retry_after = self.config.retry_after;
+                    match (self.counts.admit(ip, reserved.take()), stream) {
+                        (Some(slot), Accepted::Tcp(stream)) => {
+                            let connection = (stream, info, slot);
+                            spawn(&mut connections, connection, &f, self.config, &draining)
                         }
+                        (Some(slot), Accepted::Unix(stream)) => {
+                            let connection = (stream, info, slot);
+                            spawn(&mut connections, connection, &f, self.config, &draining)
+                        }
+                        (Some(slot), Accepted::Tls(stream, acceptor)) => {
+                            let (f, draining) = (Arc::clone(&f), draining.clone());
+                            let config = self.config;
+                            connections.spawn(async move {
+                                let _slot = slot;
+                                let timeout = config.header_timeout;
+                                let stream = handshake(&acceptor, stream, timeout).await?;
+                                let info = ConnectionInfo {
+                                    tls: Some(negotiated(&stream)),
+                                    ..info
+                                };
+                                drain_connection(stream, info, f, config, draining).await
+                            });
+                        }
+                        (None, Accepted::Tcp(stream)) => reject(&mut lingering, stream, retry_after),
+                        (None, Accepted::Unix(stream)) => reject(&mut lingering, stream, retry_after),
+                        // A 503 would take a handshake, the very work the cap
+                        // is there to save; dropping it closes the socket.
+                        (None, Accepted::Tls(..)) => {}
                     }
                 }
-                Ok::<(), Error>(())
-            });
+                // Reap finished connections as we go.
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                Some(_) = lingering.join_next(), if !lingering.is_empty() => {}
+                _ = &mut signal => break,
+            }
+        }
+        drop(self.listener);
+        if let Some(watching) = watching {
+            watching.abort();
+        }
+        let _ = drain.send(true);
+        let drained = async { while connections.join_next().await.is_some() {} };
+        if tokio::time::timeout(self.config.drain_timeout, drained)
+            .await
+            .is_err()
+        {
+            connections.shutdown().await;
+        }
+        Ok(())
+    }
+}
+
+async fn handshake(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+    timeout: Duration,
+) -> Result<TlsStream<TcpStream>> {
+    tokio::time::timeout(timeout, acceptor.accept(stream))
+        .await
+        .map_err(|_| Error::GeneralError("TLS handshake timed out".to_string()))?
+        .context("TLS handshake")
+}
+
+// Runs an admitted connection until it closes, holding its slot meanwhile.
+fn spawn<S, F, Fut>(
+    connections: &mut JoinSet<Result<()>>,
+    (stream, info, slot): (S, ConnectionInfo, ConnectionSlot),
+    f: &Arc<F>,
+    config: ServerConfig,
+    draining: &watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(State) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<State>> + Send + 'static,
+{
+    let (f, draining) = (Arc::clone(f), draining.clone());
+    connections.spawn(async move {
+        let _slot = slot;
+        drain_connection(stream, info, f, config, draining).await
+    });
+}
+
+/// Serves requests on one connection of any kind until it closes, the
+/// same way `Server` does for the connections it accepts.
+pub async fn serve_connection<S, F, Fut>(
+    stream: S,
+    info: ConnectionInfo,
+    f: Arc<F>,
+    config: ServerConfig,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(State) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<State>> + Send + 'static,
+{
+    // Never drains, the sender outlives the connection.
+    let (_drain, draining) = watch::channel(false);
+    drain_connection(stream, info, f, config, draining).await
+}
+
+async fn drain_connection<S, F, Fut>(
+    mut stream: S,
+    mut info: ConnectionInfo,
+    f: Arc<F>,
+    config: ServerConfig,
+    mut draining: watch::Receiver<bool>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(State) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<State>> + Send + 'static,
+{
+    let h2 = info
+        .tls
+        .as_ref()
+        .and_then(|tls| tls.alpn_protocol.as_deref())
+        == Some(b"h2");
+    if h2 {
+        return serve_h2(stream, info, f, config, draining, None).await;
+    }
+    let mut buffered = BytesMut::new();
+    let mut head = Vec::new();
+    loop {
+        // Between requests the connection can go away with the server.
+        // Reading is cancel safe, a byte that made it in is not lost.
+        if buffered.is_empty() {
+            let read = tokio::time::timeout(config.idle_timeout, stream.read_buf(&mut buffered));
+            tokio::select! {
+                read = read => match read {
+                    Ok(Ok(n)) if n > 0 => {}
+                    _ => break,
+                },
+                _ = draining.wait_for(|draining| *draining) => break,
+            }
+        }
+        // HTTP/2 by prior knowledge.
+        if info.request_index == 0 && buffered.starts_with(b"PRI ") {
+            match starts_with_preface(&mut stream, &mut buffered, &config).await {
+                Ok(true) => {
+                    let stream = Rewind::new(buffered.split().freeze(), stream);
+                    return serve_h2(stream, info, f, config, draining, None).await;
+                }
+                Ok(false) => {}
+                Err(Error::Rejected(_)) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let mut request = match Request::read(&mut stream, &mut buffered, &config).await {
+            Ok(request) => {
+                let connection = info.clone();
+                info.request_index += 1;
+                Arc::new(Request {
+                    connection: Some(connection),
+                    ..request
+                })
+            }
+            // What is left of the request can not be told apart
+            // from a next one, so the connection ends here.
+            Err(Error::Rejected(code)) => {
+                write_refusal(&mut stream, &mut head, code, &config).await?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if wants_h2c(&request) {
+            let switch = Response(
+                StatusLine::switching_protocols(),
+                vec![
+                    Header::connection(Connection::Upgrade),
+                    Header::upgrade("h2c"),
+                ],
+                None,
+                vec![],
+            );
+            write_response(&mut stream, &mut head, &switch, &config).await?;
+            let prefix = h2c_preface(&mut stream, &mut buffered, &request, &config).await?;
+            let stream = Rewind::new(prefix, stream);
+            return serve_h2(stream, info, f, config, draining, Some(request)).await;
+        }
+        if let Some(Expect::Other(_)) = request.headers.expect() {
+            write_refusal(&mut stream, &mut head, StatusCode::SC417, &config).await?;
+            break;
+        }
+        // A streamed body nobody read is still on the wire.
+        let mut unread = match request.body() {
+            Some(RequestBody::Stream(len)) => *len,
+            _ => 0,
+        };
+        // The client holds the body back until asked for it, so the routes
+        // see the head alone first. A refusal from it goes out without the
+        // body ever being sent.
+        let mut state = f(State::incomplete(Arc::clone(&request))).await?;
+        if request.expects_continue() && unread > 0 {
+            let continues = match &state {
+                State::Incomplete(_) => {
+                    state =
+                        State::complete(Arc::clone(&request), mk_response("", StatusCode::SC417));
+                    false
+                }
+                State::Complete(Complete(_, resp)) => {
+                    !refuses(&resp.borrow()) && unread <= MAX_BUFFERED_BODY
+                }
+                State::Deferred(_) | State::Upgrading(_) => true,
+            };
+            if continues {
+                let interim = Response(StatusLine::continue_(), vec![], None, vec![]);
+                write_response(&mut stream, &mut head, &interim, &config).await?;
+            }
+            // One small enough to buffer is read right away and routed
+            // again, the route then sees it as it would without the
+            // expectation. A larger one only a body handler takes.
+            if continues && matches!(state, State::Complete(_)) {
+                let body = BodyReader::new(&mut stream, &mut buffered, &mut unread)
+                    .with_timeout(config.body_timeout)
+                    .to_bytes()
+                    .await;
+                let body = match body {
+                    Ok(body) => body,
+                    Err(Error::Rejected(code)) => {
+                        write_refusal(&mut stream, &mut head, code, &config).await?;
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                };
+                request = Arc::new(Request {
+                    body: Some(RequestBody::Full(body)),
+                    ..(*request).clone()
+                });
+                state = f(State::incomplete(Arc::clone(&request))).await?;
+            }
         }
+        let (mut resp, upgrade) = match state {
+            State::Incomplete(_) => continue,
+            State::Complete(Complete(_, resp)) => (resp.into_inner(), None),
+            State::Upgrading(Upgrading(_, resp, handler)) => (resp.into_inner(), Some(handler)),
+            State::Deferred(Deferred(req, handler)) => {
+                let result = match req.body() {
+                    Some(RequestBody::Stream(_)) => {
+                        let body = BodyReader::new(&mut stream, &mut buffered, &mut unread)
+                            .with_timeout(config.body_timeout);
+                        handler.handle(body).await
+                    }
+                    body => {
+                        let full = body.and_then(|b| b.bytes()).unwrap_or_default();
+                        let mut remaining = full.len() as u64;
+                        let mut full = BytesMut::from(full);
+                        let mut empty = tokio::io::empty();
+                        let body = BodyReader::new(&mut empty, &mut full, &mut remaining);
+                        handler.handle(body).await
+                    }
+                };
+                let resp = match result {
+                    Ok(resp) => resp,
+                    Err(Error::Rejected(code)) => mk_response("", code).into_inner(),
+                    Err(_) => mk_response("", StatusCode::SC500).into_inner(),
+                };
+                (resp, None)
+            }
+        };
+        // HTTP/1.0 would take an interim response for the final one.
+        if !request.takes_interim() {
+            resp.3.clear();
+        }
+        // Past a switch the connection is no longer ours to close.
+        if let Some(handler) = upgrade.filter(|_| switches(&resp)) {
+            if !handler.answers() {
+                write_response(&mut stream, &mut head, &resp, &config).await?;
+            }
+            let upgraded = Upgraded::new(request, buffered.split().freeze(), Box::new(stream));
+            return handler.handle(upgraded).await;
+        }
+        // HTTP/1.0 has no chunks, a stream runs until the connection closes.
+        let streamed = resp.2.as_ref().is_some_and(|body| body.is_stream());
+        let chunked = streamed && request.takes_interim();
+        let close = unread > 0
+            || request.headers.connection() == Some(Connection::Close)
+            || *draining.borrow()
+            || (streamed && !chunked);
+        if close {
+            resp.set_header(Header::connection(Connection::Close));
+        }
+        if chunked {
+            resp.set_header(Header::transfer_encoding(TransferEncoding::Chunked));
+        }
+        if request.http_method().is_head() {
+            resp.2 = None;
+        }
+        let ended = match resp.2.take() {
+            Some(ResponseBody::Stream(body)) => {
+                write_response(&mut stream, &mut head, &resp, &config).await?;
+                write_chunks(&mut stream, body, chunked, &config, &mut draining).await?
+            }
+            body => {
+                resp.2 = body;
+                write_response(&mut stream, &mut head, &resp, &config).await?;
+                true
+            }
+        };
+        if close || !ended {
+            break;
+        }
+    }
+    // A TLS stream says close_notify here, so the client can tell the end
+    // from a cut.
+    let _ = tokio::time::timeout(config.write_timeout, stream.shutdown()).await;
+    Ok(())
+}
+
+/// Sends a streamed body chunk by chunk as it is made, unframed unless
+/// `chunked`. `false` when it ended early, because the producer failed or
+/// the server is draining; the connection has to close then.
+async fn write_chunks<S>(
+    stream: &mut S,
+    body: BodyStream,
+    chunked: bool,
+    config: &ServerConfig,
+    draining: &mut watch::Receiver<bool>,
+) -> Result<bool>
+where
+    S: AsyncWrite + Unpin,
+{
+    let (mut chunks, mut producer) = body.start();
+    let complete = loop {
+        let chunk = tokio::select! {
+            chunk = chunks.recv() => chunk,
+            _ = draining.wait_for(|draining| *draining) => break false,
+        };
+        match chunk {
+            // An empty chunk would end the body.
+            Some(chunk) if chunk.is_empty() => {}
+            Some(chunk) => write_chunk(stream, &chunk, chunked, config).await?,
+            None => break finished(&mut producer).await,
+        }
+    };
+    // Cut short by the producer, the body must not look complete.
+    if chunked && (complete || *draining.borrow()) {
+        write_chunk(stream, b"", chunked, config).await?;
+    }
+    Ok(complete)
+}
+
+// The empty chunk is the last one.
+async fn write_chunk<S>(
+    stream: &mut S,
+    chunk: &[u8],
+    chunked: bool,
+    config: &ServerConfig,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let size = match chunked {
+        true => format!("{:x}\r\n", chunk.len()),
+        false => String::new(),
+    };
+    let stall = config.write_timeout;
+    write_all_vectored(stream, size.as_bytes(), chunk, stall).await?;
+    if chunked {
+        progress(stall, "Chunk end", stream.write_all(b"\r\n")).await?;
+    }
+    progress(stall, "Flushing a chunk", stream.flush()).await
+}
+
+// Answers with `code` and ends the connection, the request being unread.
+async fn write_refusal<S>(
+    stream: &mut S,
+    head: &mut Vec<u8>,
+    code: StatusCode,
+    config: &ServerConfig,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin + 'static,
+{
+    let mut resp = mk_response("", code).into_inner();
+    resp.set_header(Header::connection(Connection::Close));
+    write_response(stream, head, &resp, config).await
+}
+
+/// Whether a response to an upgrade lets it go ahead: 101, or 2xx for a
+/// tunnel.
+// A final answer the client has to fix its request for.
+fn refuses(resp: &Response) -> bool {
+    resp.0.status_code().as_bytes()[0] == b'4'
+}
+
+fn switches(resp: &Response) -> bool {
+    matches!(resp.0.status_code().as_bytes()[0], b'1' | b'2')
+}
+
+async fn write_response<S>(
+    stream: &mut S,
+    head: &mut Vec<u8>,
+    resp: &Response,
+    config: &ServerConfig,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin + 'static,
+{
+    // `head` is reused from one response to the next.
+    resp.write_head(head);
+    let stall = config.write_timeout;
+    match &resp.2 {
+        Some(ResponseBody::File(file)) => {
+            progress(stall, "Write response", stream.write_all(head)).await?;
+            send_file(stream, file, stall).await?;
+        }
+        Some(ResponseBody::Full(body)) => write_all_vectored(stream, head, body, stall).await?,
+        // Its chunks follow the head, see `write_chunks`.
+        Some(ResponseBody::Stream(_)) | None => {
+            progress(stall, "Write response", stream.write_all(head)).await?
+        }
+    }
+    progress(stall, "Flushing the response", stream.flush()).await
+}
+
+/// Sends head and body in as few syscalls as the socket allows, without first
+/// copying them into one buffer. Each write has `stall` to get anywhere.
+async fn write_all_vectored<S>(
+    stream: &mut S,
+    head: &[u8],
+    body: &[u8],
+    stall: Duration,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let (mut head, mut body) = (head, body);
+    while !head.is_empty() || !body.is_empty() {
+        let slices = [IoSlice::new(head), IoSlice::new(body)];
+        let n = progress(stall, "Write response", stream.write_vectored(&slices)).await?;
+        if n == 0 {
+            return Err(Error::GeneralError(
+                "Connection closed while writing".to_string(),
+            ));
+        }
+        let from_head = n.min(head.len());
+        head = &head[from_head..];
+        body = &body[n - from_head..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection_info, early_hints, ok, peer_credentials, remote_addr, req_body, state, Endpoint,
+    };
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+
+    async fn send<S>(stream: &mut S, request: &[u8]) -> Result<String>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream.write_all(request).await.context("write")?;
+        let mut buf = vec![0; 1024];
+        let n = stream.read(&mut buf).await.context("read")?;
+        Ok(String::from_utf8_lossy(&buf[..n]).to_string())
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() -> Result<()> {
+        let server = Server::bind("127.0.0.1:0").await?;
+        let addr = server.local_addr()?;
+        let (stop, stopped) = oneshot::channel::<()>();
+        let routes = Arc::new(|s: State| async move {
+            if s.request().get_route() == "/slow" {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+            state().set_response(ok("done")).handle(s).map(|(s, _)| s)
+        });
+        let serving = tokio::spawn(server.serve_with_shutdown(routes, async {
+            let _ = stopped.await;
+        }));
+
+        let mut idle = TcpStream::connect(addr).await.context("connect")?;
+        let first = send(&mut idle, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+        assert!(first.starts_with("HTTP/1.1 200") && !first.contains("Connection"));
+
+        let mut busy = TcpStream::connect(addr).await.context("connect")?;
+        busy.write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .context("write")?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let _ = stop.send(());
+
+        // The request in flight still gets its response, with the news.
+        let mut buf = vec![0; 1024];
+        let n = busy.read(&mut buf).await.context("read")?;
+        let response = String::from_utf8_lossy(&buf[..n]);
+        assert!(response.starts_with("HTTP/1.1 200") && response.contains("Connection:close"));
+        // The idle one is simply closed.
+        assert_eq!(idle.read(&mut buf).await.context("read")?, 0);
+        serving.await.context("join")??;
+        assert!(TcpStream::connect(addr).await.is_err());
+        Ok(())
+    }
+
+    async fn start(config: ServerConfig) -> Result<(SocketAddr, ConnectionCounts)> {
+        let server = Server::bind("127.0.0.1:0").await?.with_config(config);
+        let (addr, counts) = (server.local_addr()?, server.connection_counts());
+        let routes = Arc::new(|s: State| async move {
+            state().set_response(ok("done")).handle(s).map(|(s, _)| s)
+        });
+        tokio::spawn(server.serve(routes));
+        Ok((addr, counts))
+    }
+
+    #[test]
+    fn test_fd_connection_limit() {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+            0
+        );
+        let connections = ServerConfig::default().max_connections as u64;
+        assert!(connections >= 1);
+        if limit.rlim_cur > RESERVED_FDS {
+            assert!(connections * 2 + RESERVED_FDS <= limit.rlim_cur);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_limits() -> Result<()> {
+        let config = ServerConfig {
+            max_connections: 1,
+            overload: Overload::Reject,
+            retry_after: Duration::from_millis(1500),
+            ..Default::default()
+        };
+        let (addr, counts) = start(config).await?;
+        let mut first = TcpStream::connect(addr).await.context("connect")?;
+        assert!(
+            send(&mut first, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await?
+                .starts_with("HTTP/1.1 200")
+        );
+        let mut second = TcpStream::connect(addr).await.context("connect")?;
+        let rejected = send(&mut second, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+        assert!(rejected.starts_with("HTTP/1.1 503") && rejected.contains("Retry-After: 2"));
+        assert_eq!(
+            (counts.active(), counts.accepted(), counts.rejected()),
+            (1, 1, 1)
+        );
+
+        let config = ServerConfig {
+            max_connections: 1,
+            ..Default::default()
+        };
+        let (addr, counts) = start(config).await?;
+        let mut first = TcpStream::connect(addr).await.context("connect")?;
+        send(&mut first, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+        // Waits in the backlog until the first one is gone.
+        let mut second = TcpStream::connect(addr).await.context("connect")?;
+        let waiting = send(&mut second, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        tokio::pin!(waiting);
+        let early = tokio::time::timeout(Duration::from_millis(100), &mut waiting).await;
+        assert!(early.is_err());
+        drop(first);
+        assert!(waiting.await?.starts_with("HTTP/1.1 200"));
+        assert_eq!((counts.active(), counts.rejected()), (1, 0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_info() -> Result<()> {
+        let server = Server::bind("127.0.0.1:0").await?;
+        let addr = server.local_addr()?;
+        let routes = Arc::new(|s: State| async move {
+            let describe = |info: Option<ConnectionInfo>| {
+                let info = info.expect("served requests have connection info");
+                ok(format!(
+                    "{} {} {}",
+                    info.local_addr.expect("a TCP connection"),
+                    info.id,
+                    info.request_index
+                ))
+            };
+            state()
+                .set_response(connection_info().flat_map(describe))
+                .handle(s)
+                .map(|(s, _)| s)
+        });
+        tokio::spawn(server.serve(routes));
+
+        let mut client = TcpStream::connect(addr).await.context("connect")?;
+        let first = send(&mut client, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+        let second = send(&mut client, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+        let body = |resp: &str| resp.rsplit("\r\n").next().unwrap_or("").to_string();
+        let (first, second) = (body(&first), body(&second));
+        assert!(first.starts_with(&addr.to_string()));
+        // Same connection, next request.
+        let connection = first.strip_suffix(" 0").unwrap_or_default();
+        assert_eq!(second, format!("{} 1", connection));
+
+        let remote = remote_addr()
+            .handle(State::incomplete(Arc::new(Request {
+                connection: Some(ConnectionInfo::new(Some(addr), None)),
+                ..crate::parse_request(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?
+            })))?
+            .1;
+        assert_eq!(remote, Some(addr));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serve_duplex() -> Result<()> {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let routes = Arc::new(|s: State| async move {
+            let describe = |info: Option<ConnectionInfo>| {
+                let info = info.expect("served requests have connection info");
+                ok(format!("{:?} {}", info.remote_addr, info.request_index))
+            };
+            state()
+                .set_response(connection_info().flat_map(describe))
+                .handle(s)
+                .map(|(s, _)| s)
+        });
+        let info = ConnectionInfo::new(None, None);
+        let serving = tokio::spawn(serve_connection(
+            server,
+            info,
+            routes,
+            ServerConfig::default(),
+        ));
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .context("write")?;
+        let mut responses = String::new();
+        client
+            .read_to_string(&mut responses)
+            .await
+            .context("read")?;
+        assert!(responses.contains("\r\n\r\nNone 0HTTP/1.1 200 OK\r\n"));
+        assert!(responses.ends_with("\r\n\r\nNone 1"));
+        serving.await.context("join")??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue() -> Result<()> {
+        let runs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = Arc::clone(&runs);
+        let routes = Arc::new(move |s: State| {
+            let counted = Arc::clone(&counted);
+            let echo = move |body: Option<RequestBody>| {
+                counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let body = body.and_then(|b| b.bytes()).unwrap_or_default();
+                ok(String::from_utf8_lossy(&body).to_string())
+            };
+            let echo = state().set_response(req_body().flat_map(echo));
+            let routes = crate::route::post("/echo")
+                .and(echo.max_body(10))
+                .unit()
+                .or(state().unit());
+            std::future::ready(routes.handle(s).map(|(s, _)| s))
+        });
+        let connect = || {
+            let (client, server) = tokio::io::duplex(4096);
+            let info = ConnectionInfo::new(None, None);
+            let serving = serve_connection(server, info, Arc::clone(&routes), Default::default());
+            tokio::spawn(serving);
+            client
+        };
+        let expecting = |target: &str, expect: &str, len: usize| {
+            format!("POST {target} HTTP/1.1\r\nHost: localhost\r\nExpect: {expect}\r\nContent-Length: {len}\r\n\r\n")
+        };
+
+        let mut client = connect();
+        let interim = send(
+            &mut client,
+            expecting("/echo", "100-continue", 5).as_bytes(),
+        )
+        .await?;
+        assert_eq!(interim, "HTTP/1.1 100 Continue\r\n\r\n");
+        let resp = send(&mut client, b"hello").await?;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(resp.ends_with("\r\n\r\nhello"));
+
+        // HTTP/1.0 knows no 100, the body comes right away.
+        let mut client = connect();
+        let request =
+            "POST /echo HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nhello";
+        let resp = send(&mut client, request.as_bytes()).await?;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // Routed on the head, then again once the body is in.
+        assert_eq!(runs.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        // Refused from the head alone, with no 100 first, whether the body
+        // would fit the buffer or not.
+        let streamed = MAX_BUFFERED_BODY as usize + 1;
+        let refused = [
+            (expecting("/echo", "100-continue", 11), "413"),
+            (expecting("/echo", "100-continue", streamed), "413"),
+            (expecting("/elsewhere", "100-continue", streamed), "417"),
+            (expecting("/echo", "something-else", 5), "417"),
+        ];
+        for (request, status) in refused {
+            let mut client = connect();
+            let resp = send(&mut client, request.as_bytes()).await?;
+            assert!(resp.starts_with(&format!("HTTP/1.1 {status} ")));
+            assert!(resp.contains("Connection:close\r\n"));
+        }
+        // The refused uploads made their answers once, from the head, and
+        // lost them to the 413.
+        assert_eq!(runs.load(std::sync::atomic::Ordering::SeqCst), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() -> Result<()> {
+        let body = "x".repeat(64 * 1024);
+        let routes = Arc::new(move |s: State| {
+            let routes = state().set_response(ok(body.clone()));
+            std::future::ready(routes.handle(s).map(|(s, _)| s))
+        });
+        let config = ServerConfig {
+            write_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let connect = || {
+            let (client, server) = tokio::io::duplex(1024);
+            let info = ConnectionInfo::new(None, None);
+            let serving = serve_connection(server, info, Arc::clone(&routes), config);
+            (client, tokio::spawn(serving))
+        };
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+        // Slower overall than the timeout, but never stalled for it.
+        let (mut client, _) = connect();
+        client.write_all(request).await.context("write")?;
+        let (mut got, mut buf) = (0, [0; 1024]);
+        loop {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            match client.read(&mut buf).await.context("read")? {
+                0 => break,
+                n => got += n,
+            }
+        }
+        assert!(got > 64 * 1024);
+
+        // A client that stops reading is dropped.
+        let (mut client, serving) = connect();
+        client.write_all(request).await.context("write")?;
+        let done = tokio::time::timeout(Duration::from_secs(5), serving).await;
+        assert!(done.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_early_hints() -> Result<()> {
+        let routes = Arc::new(|s: State| {
+            let hints = early_hints(&["</app.css>; rel=preload; as=style"]);
+            let routes = state().set_response(ok("page")).and(hints);
+            std::future::ready(routes.handle(s).map(|(s, _)| s))
+        });
+        let (mut client, server) = tokio::io::duplex(4096);
+        let info = ConnectionInfo::new(None, None);
+        tokio::spawn(serve_connection(server, info, routes, Default::default()));
+        let resp = send(&mut client, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+        assert!(resp.starts_with(
+            "HTTP/1.1 103 Early Hints\r\nLink: </app.css>; rel=preload; as=style\r\n\r\n\
+             HTTP/1.1 200 OK\r\n"
+        ));
+        let resp = send(&mut client, b"GET / HTTP/1.0\r\n\r\n").await?;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!resp.contains("Link"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serve_unix() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("serve-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).context("mkdir")?;
+        let path = dir.join("http.sock");
+        // Left behind by a server that is gone.
+        drop(std::os::unix::net::UnixListener::bind(&path).context("stale")?);
+
+        let server = Server::bind_unix_with_mode(&path, 0o600).await?;
+        let mode = std::fs::metadata(&path)
+            .context("stat")?
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(server.local_addr().is_err());
+        let routes = Arc::new(|s: State| async move {
+            let describe = |creds: Option<PeerCredentials>| {
+                let creds = creds.expect("unix connections have credentials");
+                ok(format!("{} {:?}", creds.uid, creds.pid))
+            };
+            state()
+                .set_response(peer_credentials().flat_map(describe))
+                .handle(s)
+                .map(|(s, _)| s)
+        });
+        let (stop, stopped) = oneshot::channel::<()>();
+        let serving = tokio::spawn(server.serve_with_shutdown(routes, async {
+            let _ = stopped.await;
+        }));
+        // A live socket is not taken over.
+        assert!(Server::bind_unix(&path).await.is_err());
+
+        let mut client = UnixStream::connect(&path).await.context("connect")?;
+        let resp = send(&mut client, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+        // SAFETY: getuid cannot fail.
+        let uid = unsafe { libc::getuid() };
+        let pid = std::process::id();
+        assert!(resp.ends_with(&format!("\r\n\r\n{} Some({})", uid, pid)));
+
+        drop(client);
+        let _ = stop.send(());
+        serving.await.context("join")??;
+        assert!(!path.exists());
+        std::fs::remove_dir(&dir).context("cleanup")?;
+        Ok(())
     }
 }
@@ -0,0 +1,274 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::{
+    request, BodySender, BodyStream, ContentType, Endpoint, Header, Request, Response, Result,
+};
+
+/// Time between keep-alive comments.
+pub const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// One server-sent event. Only the data is required.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn data(data: &str) -> Self {
+        Event {
+            data: data.to_string(),
+            ..Default::default()
+        }
+    }
+    /// The type a browser dispatches it as, `message` if none is given.
+    pub fn event(self, name: &str) -> Self {
+        Event {
+            event: Some(name.to_string()),
+            ..self
+        }
+    }
+    /// Sent back as `Last-Event-ID` when the client reconnects.
+    pub fn id(self, id: &str) -> Self {
+        Event {
+            id: Some(id.to_string()),
+            ..self
+        }
+    }
+    /// How long the client waits before reconnecting.
+    pub fn retry(self, after: Duration) -> Self {
+        Event {
+            retry: Some(after),
+            ..self
+        }
+    }
+    /// The event in `text/event-stream` framing. A line break would end a
+    /// name or id early and is dropped; data gets one field per line.
+    pub fn encode(&self) -> Bytes {
+        let one_line = |v: &str| v.replace(['\r', '\n', '\0'], "");
+        let mut out = String::new();
+        if let Some(event) = &self.event {
+            out.push_str(&format!("event: {}\n", one_line(event)));
+        }
+        if let Some(id) = &self.id {
+            out.push_str(&format!("id: {}\n", one_line(id)));
+        }
+        if let Some(retry) = self.retry {
+            out.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        let data = self.data.replace("\r\n", "\n").replace('\r', "\n");
+        for line in data.split('\n') {
+            out.push_str(&format!("data: {}\n", line));
+        }
+        out.push('\n');
+        Bytes::from(out)
+    }
+}
+
+/// Sends events to one client. Sending fails once the client is gone.
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    body: BodySender,
+    request: Arc<Request>,
+}
+
+impl EventSender {
+    pub fn request(&self) -> &Arc<Request> {
+        &self.request
+    }
+    /// Where a reconnecting client left off.
+    pub fn last_event_id(&self) -> Option<String> {
+        self.request.headers.last_event_id().map(|id| id.0)
+    }
+    pub async fn send(&self, event: &Event) -> Result<()> {
+        self.body.send(event.encode()).await
+    }
+    /// A line clients ignore.
+    pub async fn comment(&self, text: &str) -> Result<()> {
+        let comment = format!(": {}\n\n", text.replace(['\r', '\n'], " "));
+        self.body.send(Bytes::from(comment)).await
+    }
+    /// Resolves when the client is gone.
+    pub async fn closed(&self) {
+        self.body.closed().await
+    }
+}
+
+/// Answers with an event stream that `handler` writes to, sending a
+/// keep-alive comment every `SSE_KEEP_ALIVE`.
+pub fn sse<F, Fut>(handler: F) -> impl Endpoint<Output = RefCell<Response>>
+where
+    F: Fn(EventSender) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    sse_with_keep_alive(SSE_KEEP_ALIVE, handler)
+}
+
+/// `sse` with keep-alive comments every `keep_alive`. A client that went
+/// away is noticed at the latest when one of them can not be sent, and
+/// `handler` is then stopped.
+pub fn sse_with_keep_alive<F, Fut>(
+    keep_alive: Duration,
+    handler: F,
+) -> impl Endpoint<Output = RefCell<Response>>
+where
+    F: Fn(EventSender) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let handler = Arc::new(handler);
+    request().map(move |request| {
+        let handler = Arc::clone(&handler);
+        let body = BodyStream::new(move |body| {
+            let events = handler(EventSender {
+                body: body.clone(),
+                request: Arc::clone(&request),
+            });
+            Box::pin(async move {
+                tokio::select! {
+                    result = events => result,
+                    gone = keep_alive_comments(&body, keep_alive) => gone,
+                }
+            })
+        });
+        let mut resp = Response::stream(ContentType::EventStream, body);
+        resp.add_header(Header::cache_control("no-cache"));
+        RefCell::new(resp)
+    })
+}
+
+// Returns only once the client is gone.
+async fn keep_alive_comments(body: &BodySender, every: Duration) -> Result<()> {
+    loop {
+        tokio::time::sleep(every).await;
+        body.send(Bytes::from_static(b":\n\n")).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ok, route, serve_connection, ConnectionInfo, Context, State};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn test_encode() {
+        let event = Event::data("line one\r\nline two")
+            .event("build")
+            .id("7\n")
+            .retry(Duration::from_secs(3));
+        assert_eq!(
+            event.encode(),
+            "event: build\nid: 7\nretry: 3000\ndata: line one\ndata: line two\n\n"
+        );
+        assert_eq!(Event::data("").encode(), "data: \n\n");
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() -> Result<()> {
+        let (gone, dropped) = oneshot::channel::<()>();
+        let gone = Arc::new(std::sync::Mutex::new(Some(gone)));
+        let f = Arc::new(move |s: State| {
+            let gone = Arc::clone(&gone);
+            // Picks up after the last id seen, then ends; `/forever` never does.
+            let logs = sse_with_keep_alive(Duration::from_millis(20), |events| async move {
+                let last = events.last_event_id().and_then(|id| id.parse().ok());
+                for id in last.unwrap_or(0) + 1..=5 {
+                    let event = Event::data(&format!("step {id}")).id(&id.to_string());
+                    events.send(&event).await?;
+                    tokio::time::sleep(Duration::from_millis(30)).await;
+                }
+                Ok(())
+            });
+            let forever = sse_with_keep_alive(Duration::from_millis(20), move |_| {
+                let gone = gone.lock().unwrap().take();
+                async move {
+                    std::future::pending::<()>().await;
+                    drop(gone);
+                    Ok(())
+                }
+            });
+            let routes = route::get("/logs")
+                .set_response(logs)
+                .or(route::get("/forever").set_response(forever))
+                .or(route::get("/").set_response(ok("after")));
+            std::future::ready(routes.handle(s).map(|(s, _)| s))
+        });
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let info = ConnectionInfo::new(None, None);
+        tokio::spawn(serve_connection(
+            server,
+            info,
+            f.clone(),
+            Default::default(),
+        ));
+
+        let request = b"GET /logs HTTP/1.1\r\nHost: localhost\r\nLast-Event-ID: 3\r\n\r\n";
+        client.write_all(request).await.context("write")?;
+        let mut received = vec![];
+        while !received.ends_with(b"\r\n0\r\n\r\n") {
+            received.push(client.read_u8().await.context("read")?);
+        }
+        let received = String::from_utf8_lossy(&received);
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(received.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(received.contains("Content-Type: text/event-stream\r\n"));
+        assert!(received.contains("\r\n14\r\nid: 4\ndata: step 4\n\n\r\n"));
+        assert!(received.contains("id: 5\ndata: step 5\n\n"));
+        assert!(!received.contains("step 3"));
+        assert!(received.contains("\r\n3\r\n:\n\n\r\n"));
+
+        // Chunked, the stream left the connection usable.
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .context("write")?;
+        let mut after = vec![0; 256];
+        let n = client.read(&mut after).await.context("read")?;
+        assert!(String::from_utf8_lossy(&after[..n]).ends_with("after"));
+
+        // HTTP/1.0 has no chunks: the events come as they are, and the end
+        // of the connection is the end of the stream.
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let info = ConnectionInfo::new(None, None);
+        tokio::spawn(serve_connection(
+            server,
+            info,
+            f.clone(),
+            Default::default(),
+        ));
+        let request = b"GET /logs HTTP/1.0\r\nLast-Event-ID: 3\r\n\r\n";
+        client.write_all(request).await.context("write")?;
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.context("read")?;
+        let received = String::from_utf8_lossy(&received);
+        assert!(!received.contains("Transfer-Encoding"));
+        assert!(received.contains("Connection:close\r\n"));
+        assert!(received.contains("\r\n\r\nid: 4\ndata: step 4\n\n"));
+        assert!(received.contains("id: 5\ndata: step 5\n\n"));
+        assert!(received.ends_with(":\n\n"));
+
+        // A client that goes away stops the handler at the next comment.
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let info = ConnectionInfo::new(None, None);
+        let serving = tokio::spawn(serve_connection(server, info, f, Default::default()));
+        client
+            .write_all(b"GET /forever HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .context("write")?;
+        let mut head = vec![0; 256];
+        client.read(&mut head).await.context("read")?;
+        drop(client);
+        let stopped = tokio::time::timeout(Duration::from_secs(1), dropped).await;
+        assert!(matches!(stopped, Ok(Err(_))));
+        assert!(serving.await.context("join")?.is_err());
+        Ok(())
+    }
+}
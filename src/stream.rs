@@ -0,0 +1,66 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use crate::{BoxFuture, Error, Result};
+
+// Chunks produced ahead of the connection sending them.
+const QUEUED_CHUNKS: usize = 8;
+
+/// Where a streamed body goes, one chunk at a time. Sending waits while the
+/// connection is behind, and fails once the client is gone.
+#[derive(Debug, Clone)]
+pub struct BodySender(mpsc::Sender<Bytes>);
+
+impl BodySender {
+    pub async fn send(&self, chunk: Bytes) -> Result<()> {
+        self.0
+            .send(chunk)
+            .await
+            .map_err(|_| Error::GeneralError("Client disconnected".to_string()))
+    }
+    /// Resolves when the client is gone, for producers with nothing to send.
+    pub async fn closed(&self) {
+        self.0.closed().await
+    }
+}
+
+type ProducerFn = dyn Fn(BodySender) -> BoxFuture<'static, Result<()>> + Send + Sync;
+
+/// A response body of unknown length, made while it is sent. HTTP/1.1
+/// sends it chunked, so the connection can still be kept alive. A producer
+/// that fails cuts the body short, and the client sees it was. To HTTP/1.0
+/// it goes as it is, up to the end of the connection.
+#[derive(Clone)]
+pub struct BodyStream(Arc<ProducerFn>);
+
+impl BodyStream {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(BodySender) -> BoxFuture<'static, Result<()>> + Send + Sync + 'static,
+    {
+        BodyStream(Arc::new(f))
+    }
+    /// Runs the producer. It is stopped when the task set is dropped, and
+    /// the chunks end when it returns.
+    pub(crate) fn start(&self) -> (mpsc::Receiver<Bytes>, JoinSet<Result<()>>) {
+        let (sender, chunks) = mpsc::channel(QUEUED_CHUNKS);
+        let mut producer = JoinSet::new();
+        producer.spawn((self.0)(BodySender(sender)));
+        (chunks, producer)
+    }
+}
+
+impl Debug for BodyStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BodyStream")
+    }
+}
+
+/// Whether the producer returned without an error.
+pub(crate) async fn finished(producer: &mut JoinSet<Result<()>>) -> bool {
+    matches!(producer.join_next().await, Some(Ok(Ok(()))))
+}
@@ -0,0 +1,476 @@
+use crate::{Context, Error, Result, TlsInfo};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Certificates and options for a TLS listener. All files are PEM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    /// The server certificate first, then its intermediates.
+    pub cert_chain: PathBuf,
+    pub key: PathBuf,
+    /// Offered in order of preference. A client offering none of them is
+    /// refused.
+    pub alpn_protocols: Vec<Vec<u8>>,
+    pub client_auth: ClientAuth,
+}
+
+/// Whether clients have to show a certificate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientAuth {
+    None,
+    /// Clients may show a certificate signed by one of the CAs in the file.
+    Optional(PathBuf),
+    /// Clients must show a certificate signed by one of the CAs in the file.
+    Required(PathBuf),
+}
+
+impl TlsConfig {
+    /// HTTP/1.1 only, no client certificates. Add `h2` to
+    /// `alpn_protocols` to offer HTTP/2.
+    pub fn new<P: AsRef<Path>, K: AsRef<Path>>(cert_chain: P, key: K) -> Self {
+        TlsConfig {
+            cert_chain: cert_chain.as_ref().to_path_buf(),
+            key: key.as_ref().to_path_buf(),
+            alpn_protocols: vec![b"http/1.1".to_vec()],
+            client_auth: ClientAuth::None,
+        }
+    }
+
+    /// Reads the files and builds what handshakes are done with.
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let provider = Arc::new(ring::default_provider());
+        let certs = load_certs(&self.cert_chain)?;
+        let key = load_key(&self.key)?;
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .context("TLS versions")?;
+        let builder = match &self.client_auth {
+            ClientAuth::None => builder.with_no_client_auth(),
+            ClientAuth::Optional(ca) => builder.with_client_cert_verifier(
+                WebPkiClientVerifier::builder_with_provider(load_roots(ca)?, provider)
+                    .allow_unauthenticated()
+                    .build()
+                    .context("Client verifier")?,
+            ),
+            ClientAuth::Required(ca) => builder.with_client_cert_verifier(
+                WebPkiClientVerifier::builder_with_provider(load_roots(ca)?, provider)
+                    .build()
+                    .context("Client verifier")?,
+            ),
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .context("Certificate and key")?;
+        config.alpn_protocols = self.alpn_protocols.clone();
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+// What a certificate file looked like when last read. Rotations often
+// rename a new file into place, hence the inode.
+type Stamp = Option<(SystemTime, u64, u64)>;
+
+/// The handshake setup of a TLS listener, which can be read again from its
+/// files while the server runs. Clones share it. Connections keep the setup
+/// they were accepted with.
+#[derive(Clone)]
+pub struct TlsReloader {
+    config: Arc<TlsConfig>,
+    current: Arc<RwLock<TlsAcceptor>>,
+    stamps: Arc<Mutex<Vec<Stamp>>>,
+}
+
+impl TlsReloader {
+    pub fn new(config: TlsConfig) -> Result<Self> {
+        let stamps = Mutex::new(config.stamps());
+        Ok(TlsReloader {
+            current: Arc::new(RwLock::new(config.acceptor()?)),
+            config: Arc::new(config),
+            stamps: Arc::new(stamps),
+        })
+    }
+
+    /// Reads the files again for the handshakes to come. On an error the
+    /// setup in use stays.
+    pub fn reload(&self) -> Result<()> {
+        let acceptor = self.config.acceptor()?;
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = acceptor;
+        Ok(())
+    }
+
+    pub(crate) fn acceptor(&self) -> TlsAcceptor {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    // Whether a file changed since the last call.
+    fn changed(&self) -> bool {
+        let stamps = self.config.stamps();
+        let mut last = self.stamps.lock().unwrap_or_else(|e| e.into_inner());
+        let changed = *last != stamps;
+        *last = stamps;
+        changed
+    }
+
+    /// Reloads on SIGHUP and when a file changes, looking every `poll`.
+    /// Failures are logged and the old setup kept.
+    pub(crate) async fn watch(self, poll: Duration) {
+        let mut hangup = signal(SignalKind::hangup()).ok();
+        let mut ticks = tokio::time::interval(poll);
+        loop {
+            let hangup = async {
+                match hangup.as_mut() {
+                    Some(hangup) => hangup.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = hangup => {}
+                _ = ticks.tick() => {
+                    if !self.changed() {
+                        continue;
+                    }
+                }
+            }
+            if let Err(e) = self.reload() {
+                eprintln!("Keeping the old TLS setup, reload failed: {e:?}");
+            }
+        }
+    }
+}
+
+impl TlsConfig {
+    fn stamps(&self) -> Vec<Stamp> {
+        let ca = match &self.client_auth {
+            ClientAuth::None => None,
+            ClientAuth::Optional(ca) | ClientAuth::Required(ca) => Some(ca),
+        };
+        [Some(&self.cert_chain), Some(&self.key), ca]
+            .into_iter()
+            .flatten()
+            .map(|path| {
+                use std::os::unix::fs::MetadataExt;
+                let meta = std::fs::metadata(path).ok()?;
+                Some((meta.modified().ok()?, meta.len(), meta.ino()))
+            })
+            .collect()
+    }
+}
+
+/// What the handshake of `stream` settled on.
+pub(crate) fn negotiated(stream: &TlsStream<TcpStream>) -> TlsInfo {
+    let (_, conn) = stream.get_ref();
+    TlsInfo {
+        version: conn
+            .protocol_version()
+            .map(|v| format!("{:?}", v))
+            .unwrap_or_default(),
+        cipher_suite: conn
+            .negotiated_cipher_suite()
+            .map(|s| format!("{:?}", s.suite()))
+            .unwrap_or_default(),
+        alpn_protocol: conn.alpn_protocol().map(|p| p.to_vec()),
+        server_name: conn.server_name().map(|s| s.to_string()),
+        peer_certificates: conn
+            .peer_certificates()
+            .map(|certs| certs.iter().map(|c| c.to_vec()).collect())
+            .unwrap_or_default(),
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("Open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .with_context(|| format!("Certificates in {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(Error::GeneralError(format!(
+            "No certificates in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .with_context(|| format!("Key in {}", path.display()))?
+        .ok_or_else(|| Error::GeneralError(format!("No private key in {}", path.display())))
+}
+
+fn load_roots(path: &Path) -> Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).context("CA certificate")?;
+    }
+    Ok(Arc::new(roots))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ok, peer_certificate, state, tls_info, Endpoint, Serve, Server, ServerConfig, State,
+    };
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::pki_types::{PrivatePkcs8KeyDer, ServerName};
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::{client, TlsConnector};
+
+    struct Pki {
+        ca: CertificateDer<'static>,
+        client: CertificateDer<'static>,
+        client_key: Vec<u8>,
+    }
+
+    // A CA, a server certificate for localhost and a client certificate,
+    // as PEM files in `dir`.
+    fn write_pki(dir: &Path) -> Result<Pki> {
+        let ca_key = KeyPair::generate().context("key")?;
+        let mut params = CertificateParams::new(vec![]).context("params")?;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).context("CA")?;
+
+        let server_key = KeyPair::generate().context("key")?;
+        let server = CertificateParams::new(vec!["localhost".to_string()])
+            .context("params")?
+            .signed_by(&server_key, &ca, &ca_key)
+            .context("server")?;
+
+        let client_key = KeyPair::generate().context("key")?;
+        let mut params = CertificateParams::new(vec![]).context("params")?;
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client = params
+            .signed_by(&client_key, &ca, &ca_key)
+            .context("client")?;
+
+        let write = |name: &str, pem: String| std::fs::write(dir.join(name), pem);
+        write("ca.pem", ca.pem()).context("write")?;
+        write("server.pem", server.pem()).context("write")?;
+        write("server.key", server_key.serialize_pem()).context("write")?;
+        Ok(Pki {
+            ca: ca.der().clone(),
+            client: client.der().clone(),
+            client_key: client_key.serialize_der(),
+        })
+    }
+
+    async fn connect(
+        pki: &Pki,
+        addr: std::net::SocketAddr,
+        with_cert: bool,
+    ) -> Result<client::TlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca.clone()).context("root")?;
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .context("versions")?
+            .with_root_certificates(roots);
+        let mut config = if with_cert {
+            let key = PrivatePkcs8KeyDer::from(pki.client_key.clone());
+            builder
+                .with_client_auth_cert(vec![pki.client.clone()], key.into())
+                .context("client cert")?
+        } else {
+            builder.with_no_client_auth()
+        };
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let name = ServerName::try_from("localhost").context("name")?;
+        let tcp = TcpStream::connect(addr).await.context("connect")?;
+        TlsConnector::from(Arc::new(config))
+            .connect(name, tcp)
+            .await
+            .context("handshake")
+    }
+
+    async fn get(pki: &Pki, addr: std::net::SocketAddr, with_cert: bool) -> Result<String> {
+        let mut stream = connect(pki, addr, with_cert).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .context("write")?;
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.context("read")?;
+        Ok(resp)
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("mutual-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).context("mkdir")?;
+        let pki = write_pki(&dir)?;
+        let config = TlsConfig {
+            client_auth: ClientAuth::Required(dir.join("ca.pem")),
+            ..TlsConfig::new(dir.join("server.pem"), dir.join("server.key"))
+        };
+        let server = Server::bind_tls("127.0.0.1:0", config).await?;
+        let addr = server.local_addr()?;
+        let routes = Arc::new(|s: State| async move {
+            let tls = tls_info().handle(s.clone())?.1.expect("a TLS connection");
+            let cert = peer_certificate().handle(s.clone())?.1;
+            let body = format!(
+                "{} {} {}",
+                tls.version,
+                String::from_utf8_lossy(&tls.alpn_protocol.unwrap_or_default()),
+                cert.map(|c| c.len()).unwrap_or(0)
+            );
+            state().set_response(ok(body)).handle(s).map(|(s, _)| s)
+        });
+        tokio::spawn(server.serve(routes));
+
+        let resp = get(&pki, addr, true).await?;
+        let expected = format!("TLSv1_3 http/1.1 {}", pki.client.len());
+        assert!(resp.starts_with("HTTP/1.1 200") && resp.ends_with(&expected));
+        // No certificate, no connection.
+        assert!(get(&pki, addr, false).await.is_err());
+
+        std::fs::remove_dir_all(&dir).context("cleanup")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_over_capacity() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("tls-capacity-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).context("mkdir")?;
+        let pki = write_pki(&dir)?;
+        let config = TlsConfig::new(dir.join("server.pem"), dir.join("server.key"));
+        let server = Server::bind_tls("127.0.0.1:0", config)
+            .await?
+            .with_config(ServerConfig {
+                max_connections_per_ip: Some(1),
+                ..Default::default()
+            });
+        let addr = server.local_addr()?;
+        let routes =
+            Arc::new(
+                |s: State| async move { state().set_response(ok("")).handle(s).map(|(s, _)| s) },
+            );
+        tokio::spawn(server.serve(routes));
+
+        let _open = connect(&pki, addr, false).await?;
+        // Closed without a handshake, let alone a 503.
+        let refused = tokio::time::timeout(Duration::from_secs(1), connect(&pki, addr, false));
+        assert!(matches!(refused.await, Ok(Err(_))));
+
+        std::fs::remove_dir_all(&dir).context("cleanup")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_alpn_h2() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("alpn-h2-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).context("mkdir")?;
+        let pki = write_pki(&dir)?;
+        let config = TlsConfig {
+            alpn_protocols: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            ..TlsConfig::new(dir.join("server.pem"), dir.join("server.key"))
+        };
+        let server = Server::bind_tls("127.0.0.1:0", config).await?;
+        let addr = server.local_addr()?;
+        let routes = Arc::new(|s: State| async move {
+            state()
+                .set_response(ok("over h2"))
+                .handle(s)
+                .map(|(s, _)| s)
+        });
+        tokio::spawn(server.serve(routes));
+
+        let stream = connect(&pki, addr, false).await?;
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+        let (client, conn) = h2::client::handshake(stream).await.context("h2")?;
+        tokio::spawn(conn);
+        let req = http::Request::get("https://localhost/")
+            .body(())
+            .context("request")?;
+        let (resp, _) = client
+            .ready()
+            .await
+            .context("ready")?
+            .send_request(req, true)
+            .context("send")?;
+        let resp = resp.await.context("response")?;
+        assert_eq!(resp.status(), 200);
+        let body = resp
+            .into_body()
+            .data()
+            .await
+            .expect("a body")
+            .context("body")?;
+        assert_eq!(&body[..], b"over h2");
+
+        std::fs::remove_dir_all(&dir).context("cleanup")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reload() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("reload-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).context("mkdir")?;
+        let old = write_pki(&dir)?;
+        let config = TlsConfig::new(dir.join("server.pem"), dir.join("server.key"));
+        let server = Server::bind_tls("127.0.0.1:0", config)
+            .await?
+            .with_config(ServerConfig {
+                cert_poll: Duration::from_millis(20),
+                ..Default::default()
+            });
+        let addr = server.local_addr()?;
+        let reloader = server.tls_reloader().expect("a TLS listener");
+        let routes = Arc::new(|s: State| async move {
+            state().set_response(ok("done")).handle(s).map(|(s, _)| s)
+        });
+        tokio::spawn(server.serve(routes));
+        let mut kept = connect(&old, addr, false).await?;
+        let mut buf = vec![0; 1024];
+        kept.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .context("write")?;
+        assert!(kept.read(&mut buf).await.context("read")? > 0);
+
+        // Rotated to a new CA: picked up without a signal.
+        let new = write_pki(&dir)?;
+        let mut waited = Duration::ZERO;
+        while get(&new, addr, false).await.is_err() {
+            assert!(
+                waited < Duration::from_secs(5),
+                "new certificate not loaded"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            waited += Duration::from_millis(20);
+        }
+        assert!(get(&old, addr, false).await.is_err());
+        // The open connection is unaffected.
+        kept.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .context("write")?;
+        let n = kept.read(&mut buf).await.context("read")?;
+        assert!(String::from_utf8_lossy(&buf[..n]).starts_with("HTTP/1.1 200"));
+
+        // A broken file leaves the last good setup in place.
+        std::fs::write(dir.join("server.pem"), "not a certificate").context("write")?;
+        assert!(reloader.reload().is_err());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(get(&new, addr, false).await?.starts_with("HTTP/1.1 200"));
+
+        std::fs::remove_dir_all(&dir).context("cleanup")?;
+        Ok(())
+    }
+}
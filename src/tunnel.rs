@@ -0,0 +1,352 @@
+use std::cell::RefCell;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout_at, Instant};
+
+use crate::{
+    connect, mk_response, request, Authority, Connection, Context, Endpoint, Error, Header,
+    Response, Result, StatusCode, StatusLine, Switch, UpgradeHandler, Upgraded,
+};
+
+const RELAY_CHUNK: usize = 16 * 1024;
+
+/// Where CONNECT may open tunnels to, and for how long.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TunnelConfig {
+    /// Hosts tunnels may go to, `*.example.com` for any below it. Empty
+    /// allows every host. Hosts are matched as the client names them, bar
+    /// a trailing dot.
+    pub allow: Vec<String>,
+    /// Hosts never tunnelled to, allowed or not.
+    pub deny: Vec<String>,
+    /// Ports tunnels may go to. Empty allows every port.
+    pub ports: Vec<u16>,
+    /// Whether tunnels may go to loopback, private and link-local
+    /// addresses. Every address a host resolves to is checked, whatever
+    /// its name.
+    pub allow_private: bool,
+    /// Time allowed for reaching the upstream: 504 after it.
+    pub connect_timeout: Duration,
+    /// A tunnel with nothing sent either way for this long is closed.
+    pub idle_timeout: Duration,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        TunnelConfig {
+            allow: vec![],
+            deny: vec![],
+            ports: vec![443],
+            allow_private: false,
+            connect_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+impl TunnelConfig {
+    pub fn permits(&self, to: &Authority) -> bool {
+        // `example.com.` is `example.com`, fully qualified.
+        let host = to.host.trim_end_matches('.').to_ascii_lowercase();
+        let matches = |pattern: &String| {
+            let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+            match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.ends_with('.')),
+                None => host == pattern,
+            }
+        };
+        (self.ports.is_empty() || self.ports.contains(&to.port))
+            && (self.allow.is_empty() || self.allow.iter().any(matches))
+            && !self.deny.iter().any(matches)
+    }
+    pub fn permits_addr(&self, addr: IpAddr) -> bool {
+        self.allow_private || !is_internal(addr)
+    }
+}
+
+// Loopback, private and link-local addresses, and the unspecified one that
+// stands for the host itself.
+fn is_internal(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => {
+            v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified()
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_internal(IpAddr::V4(v4)),
+            None => {
+                v6.is_loopback()
+                    || v6.is_unspecified()
+                    // Unique local fc00::/7 and link-local fe80::/10.
+                    || v6.segments()[0] & 0xfe00 == 0xfc00
+                    || v6.segments()[0] & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+/// Answers CONNECT with a tunnel to the authority in its target: 200 once
+/// the upstream is reached, after which bytes are copied both ways until
+/// either side closes. A target that is no authority is answered 400, one
+/// `config` does not permit, by name or by any address it resolves to, 403,
+/// and an upstream that can not be reached 502, or 504 when reaching it
+/// timed out.
+pub fn connect_tunnel(config: TunnelConfig) -> impl Endpoint<Output = Switch> {
+    let config = Arc::new(config);
+    connect().and(request()).map(move |(_, req)| {
+        let refuse = |code| {
+            Switch(
+                mk_response("", code),
+                UpgradeHandler::new(|_| Box::pin(async { Ok(()) })),
+            )
+        };
+        let to = match req.target().authority() {
+            Some(to) => to,
+            None => return refuse(StatusCode::SC400),
+        };
+        if !config.permits(&to) {
+            return refuse(StatusCode::SC403);
+        }
+        let config = Arc::clone(&config);
+        let tunnel = UpgradeHandler::answering(move |client| {
+            let (to, config) = (to.clone(), Arc::clone(&config));
+            Box::pin(async move { tunnel(client, &to, &config).await })
+        });
+        Switch(RefCell::new(established()), tunnel)
+    })
+}
+
+// A 2xx to CONNECT has no length, what follows is the tunnel.
+fn established() -> Response {
+    Response(StatusLine::ok(), vec![], None, vec![])
+}
+
+async fn tunnel(mut client: Upgraded, to: &Authority, config: &TunnelConfig) -> Result<()> {
+    let deadline = Instant::now() + config.connect_timeout;
+    let resolving = tokio::net::lookup_host((to.host.as_str(), to.port));
+    let addrs: Vec<SocketAddr> = match timeout_at(deadline, resolving).await {
+        Ok(Ok(addrs)) => addrs.collect(),
+        Ok(Err(_)) => return client.respond(refusal(StatusCode::SC502)).await,
+        Err(_) => return client.respond(refusal(StatusCode::SC504)).await,
+    };
+    // The addresses checked are the ones connected to; the name is not
+    // looked up again.
+    if !addrs.iter().all(|addr| config.permits_addr(addr.ip())) {
+        return client.respond(refusal(StatusCode::SC403)).await;
+    }
+    let connecting = TcpStream::connect(&addrs[..]);
+    let mut upstream = match timeout_at(deadline, connecting).await {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(_)) => return client.respond(refusal(StatusCode::SC502)).await,
+        Err(_) => return client.respond(refusal(StatusCode::SC504)).await,
+    };
+    client.respond(established()).await?;
+    relay(&mut client, &mut upstream, config.idle_timeout).await
+}
+
+fn refusal(code: StatusCode) -> Response {
+    let mut resp = mk_response("", code).into_inner();
+    resp.set_header(Header::connection(Connection::Close));
+    resp
+}
+
+/// Copies bytes both ways until both sides are done, or neither sent
+/// anything for `idle`. A side that is done has the other's writing shut.
+async fn relay<A, B>(a: &mut A, b: &mut B, idle: Duration) -> Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut from_a, mut from_b) = (vec![0; RELAY_CHUNK], vec![0; RELAY_CHUNK]);
+    let (mut a_open, mut b_open) = (true, true);
+    while a_open || b_open {
+        tokio::select! {
+            n = a.read(&mut from_a), if a_open => {
+                let n = n.context("Read tunnel")?;
+                pass_on(b, &from_a[..n], idle).await?;
+                a_open = n > 0;
+            }
+            n = b.read(&mut from_b), if b_open => {
+                let n = n.context("Read tunnel")?;
+                pass_on(a, &from_b[..n], idle).await?;
+                b_open = n > 0;
+            }
+            _ = tokio::time::sleep(idle) => break,
+        }
+    }
+    Ok(())
+}
+
+// An empty chunk is the end of the other side's.
+async fn pass_on<W>(to: &mut W, chunk: &[u8], idle: Duration) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let sent = async {
+        match chunk.is_empty() {
+            true => to.shutdown().await,
+            false => to.write_all(chunk).await,
+        }
+    };
+    tokio::time::timeout(idle, sent)
+        .await
+        .map_err(|_| Error::GeneralError("Tunnel stalled".to_string()))?
+        .context("Write tunnel")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serve_connection, state, ConnectionInfo, State};
+    use tokio::io::DuplexStream;
+    use tokio::net::TcpListener;
+
+    async fn read_head(client: &mut DuplexStream) -> Result<String> {
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.context("read")?);
+        }
+        Ok(String::from_utf8_lossy(&head).to_string())
+    }
+
+    #[test]
+    fn test_permits() {
+        let config = TunnelConfig {
+            allow: vec!["*.example.com".to_string(), "localhost".to_string()],
+            deny: vec!["admin.example.com".to_string()],
+            ..Default::default()
+        };
+        let to = |host: &str, port| Authority {
+            host: host.to_string(),
+            port,
+        };
+        assert!(config.permits(&to("api.Example.com", 443)));
+        assert!(config.permits(&to("localhost", 443)));
+        assert!(!config.permits(&to("api.example.com", 22)));
+        assert!(!config.permits(&to("admin.example.com", 443)));
+        assert!(!config.permits(&to("admin.example.com.", 443)));
+        assert!(config.permits(&to("api.example.com.", 443)));
+        assert!(!config.permits(&to("example.com", 443)));
+        assert!(!config.permits(&to("badexample.com", 443)));
+
+        let internal = [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ];
+        for addr in internal {
+            let addr = addr.parse().unwrap();
+            assert!(!config.permits_addr(addr), "{addr}");
+            let private = TunnelConfig {
+                allow_private: true,
+                ..config.clone()
+            };
+            assert!(private.permits_addr(addr));
+        }
+        assert!(config.permits_addr("93.184.216.34".parse().unwrap()));
+        assert!(config.permits_addr("2606:2800::1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_tunnel() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.context("bind")?;
+        let port = listener.local_addr().context("addr")?.port();
+        tokio::spawn(async move {
+            let (mut upstream, _) = listener.accept().await.context("accept")?;
+            let (mut from, mut to) = upstream.split();
+            tokio::io::copy(&mut from, &mut to).await.context("echo")
+        });
+        let closed = TcpListener::bind("127.0.0.1:0").await.context("bind")?;
+        let closed_port = closed.local_addr().context("addr")?.port();
+        drop(closed);
+        let config = TunnelConfig {
+            deny: vec!["localhost".to_string()],
+            ports: vec![port, closed_port],
+            allow_private: true,
+            idle_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let routes = |config: TunnelConfig| {
+            Arc::new(move |s: State| {
+                let routes = state().set_switch(connect_tunnel(config.clone()));
+                std::future::ready(routes.handle(s).map(|(s, _)| s))
+            })
+        };
+        let f = routes(config.clone());
+        let serve = |f| {
+            let (client, server) = tokio::io::duplex(4096);
+            let info = ConnectionInfo::new(None, None);
+            tokio::spawn(serve_connection(server, info, f, Default::default()));
+            client
+        };
+
+        // Bytes sent along with the request go through too.
+        let mut client = serve(f.clone());
+        let request =
+            format!("CONNECT 127.0.0.1:{port} HTTP/1.1\r\nHost: 127.0.0.1:{port}\r\n\r\nhello");
+        client
+            .write_all(request.as_bytes())
+            .await
+            .context("write")?;
+        assert_eq!(read_head(&mut client).await?, "HTTP/1.1 200 OK\r\n\r\n");
+        let mut echoed = [0; 5];
+        client.read_exact(&mut echoed).await.context("read")?;
+        assert_eq!(&echoed, b"hello");
+        // Left idle, the tunnel closes.
+        let closing = tokio::time::timeout(Duration::from_secs(1), client.read(&mut echoed));
+        assert!(matches!(closing.await, Ok(Ok(0))));
+
+        let mut client = serve(f.clone());
+        let refused = [
+            (
+                format!("CONNECT LocalHost:{port} HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+                "403",
+            ),
+            (
+                "CONNECT /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n".to_string(),
+                "400",
+            ),
+            (
+                format!("CONNECT 127.0.0.1:{closed_port} HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+                "502",
+            ),
+        ];
+        for (request, code) in refused {
+            client
+                .write_all(request.as_bytes())
+                .await
+                .context("write")?;
+            let head = read_head(&mut client).await?;
+            assert!(head.starts_with(&format!("HTTP/1.1 {code} ")), "{head}");
+        }
+
+        // Denied by name, with or without the root's dot; by address
+        // however the host spells it.
+        let f = routes(TunnelConfig {
+            allow_private: false,
+            ..config
+        });
+        for host in ["localhost.", "127.0.0.1", "127.1", "2130706433", "[::1]"] {
+            let mut client = serve(f.clone());
+            let request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            client
+                .write_all(request.as_bytes())
+                .await
+                .context("write")?;
+            let head = read_head(&mut client).await?;
+            assert!(head.starts_with("HTTP/1.1 403 "), "{host}: {head}");
+        }
+        Ok(())
+    }
+}
@@ -1,49 +1,154 @@
 use crate::Error::GeneralError;
-use crate::{Error, Result};
+use crate::{BodyStream, Error, FileBody, Result};
 use bytes::Bytes;
 use derive_more::{Deref, From};
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum HttpVersion {
     HttpOne,
+    /// Knows no interim responses.
+    HttpOneZero,
+}
+impl HttpVersion {
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            HttpVersion::HttpOne => b"HTTP/1.1",
+            HttpVersion::HttpOneZero => b"HTTP/1.0",
+        }
+    }
 }
 impl From<HttpVersion> for Vec<u8> {
     fn from(value: HttpVersion) -> Self {
-        match value {
-            HttpVersion::HttpOne => b"HTTP/1.1".to_vec(),
-        }
+        value.as_bytes().to_vec()
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum StatusCode {
+    SC100,
+    SC101,
+    SC103,
     SC200,
     SC201,
+    SC204,
+    SC304,
+    SC400,
+    SC403,
     SC404,
+    SC408,
+    SC409,
+    SC411,
+    SC412,
+    SC413,
+    SC414,
+    SC417,
+    SC426,
+    SC431,
+    SC500,
+    SC501,
+    SC502,
+    SC503,
+    SC504,
+}
+impl StatusCode {
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            StatusCode::SC100 => b"100",
+            StatusCode::SC101 => b"101",
+            StatusCode::SC103 => b"103",
+            StatusCode::SC200 => b"200",
+            StatusCode::SC201 => b"201",
+            StatusCode::SC204 => b"204",
+            StatusCode::SC304 => b"304",
+            StatusCode::SC400 => b"400",
+            StatusCode::SC403 => b"403",
+            StatusCode::SC404 => b"404",
+            StatusCode::SC408 => b"408",
+            StatusCode::SC409 => b"409",
+            StatusCode::SC411 => b"411",
+            StatusCode::SC412 => b"412",
+            StatusCode::SC413 => b"413",
+            StatusCode::SC414 => b"414",
+            StatusCode::SC417 => b"417",
+            StatusCode::SC426 => b"426",
+            StatusCode::SC431 => b"431",
+            StatusCode::SC500 => b"500",
+            StatusCode::SC501 => b"501",
+            StatusCode::SC502 => b"502",
+            StatusCode::SC503 => b"503",
+            StatusCode::SC504 => b"504",
+        }
+    }
 }
 impl From<StatusCode> for Vec<u8> {
     fn from(value: StatusCode) -> Self {
-        match value {
-            StatusCode::SC200 => b"200".to_vec(),
-            StatusCode::SC201 => b"201".to_vec(),
-            StatusCode::SC404 => b"404".to_vec(),
-        }
+        value.as_bytes().to_vec()
     }
 }
 #[derive(Debug, Copy, Clone)]
 pub enum Reason {
     Ok,
     Created,
+    NoContent,
+    NotModified,
+    BadRequest,
     NotFound,
+    Conflict,
+    PreconditionFailed,
+    InternalServerError,
+    ContentTooLarge,
+    UriTooLong,
+    RequestHeaderFieldsTooLarge,
+    RequestTimeout,
+    ServiceUnavailable,
+    SwitchingProtocols,
+    UpgradeRequired,
+    NotImplemented,
+    Continue,
+    ExpectationFailed,
+    EarlyHints,
+    Forbidden,
+    BadGateway,
+    GatewayTimeout,
+    LengthRequired,
 }
 
+impl Reason {
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            Reason::Ok => b"OK",
+            Reason::Created => b"Created",
+            Reason::NoContent => b"No Content",
+            Reason::NotModified => b"Not Modified",
+            Reason::BadRequest => b"Bad Request",
+            Reason::NotFound => b"Not Found",
+            Reason::Conflict => b"Conflict",
+            Reason::PreconditionFailed => b"Precondition Failed",
+            Reason::InternalServerError => b"Internal Server Error",
+            Reason::ContentTooLarge => b"Content Too Large",
+            Reason::UriTooLong => b"URI Too Long",
+            Reason::RequestHeaderFieldsTooLarge => b"Request Header Fields Too Large",
+            Reason::RequestTimeout => b"Request Timeout",
+            Reason::ServiceUnavailable => b"Service Unavailable",
+            Reason::SwitchingProtocols => b"Switching Protocols",
+            Reason::UpgradeRequired => b"Upgrade Required",
+            Reason::NotImplemented => b"Not Implemented",
+            Reason::Continue => b"Continue",
+            Reason::ExpectationFailed => b"Expectation Failed",
+            Reason::EarlyHints => b"Early Hints",
+            Reason::Forbidden => b"Forbidden",
+            Reason::BadGateway => b"Bad Gateway",
+            Reason::GatewayTimeout => b"Gateway Timeout",
+            Reason::LengthRequired => b"Length Required",
+        }
+    }
+}
 impl From<Reason> for Vec<u8> {
     fn from(value: Reason) -> Self {
-        match value {
-            Reason::Ok => b"OK".to_vec(),
-            Reason::Created => b"Created".to_vec(),
-            Reason::NotFound => b"Not Found".to_vec(),
-        }
+        value.as_bytes().to_vec()
     }
 }
 
@@ -54,33 +159,107 @@ pub struct Host(String);
 pub struct UserAgent(pub String);
 #[derive(Debug, Clone, From, Deref, PartialEq)]
 pub struct Accept(String);
+
+impl Accept {
+    /// Picks the offered media type the client weighs highest; ties go to the
+    /// earlier offer. `*/*` and `type/*` ranges count for every offer they cover.
+    pub fn prefers<'a>(&self, offers: &[&'a str]) -> Option<&'a str> {
+        let ranges: Vec<(&str, f32)> = self
+            .0
+            .split(',')
+            .map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let media = parts.next().unwrap_or("");
+                let q = parts
+                    .find_map(|p| p.strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                (media, q)
+            })
+            .collect();
+        let quality = |offer: &str| {
+            ranges
+                .iter()
+                .filter(|(media, _)| {
+                    *media == offer
+                        || *media == "*/*"
+                        || media
+                            .strip_suffix("/*")
+                            .is_some_and(|t| offer.starts_with(&format!("{}/", t)))
+                })
+                .map(|(_, q)| *q)
+                .fold(None, |best: Option<f32>, q| {
+                    Some(best.map_or(q, |b| b.max(q)))
+                })
+        };
+        offers
+            .iter()
+            .filter_map(|offer| quality(offer).map(|q| (*offer, q)))
+            .filter(|(_, q)| *q > 0.0)
+            .fold(
+                None,
+                |best: Option<(&'a str, f32)>, (offer, q)| match best {
+                    Some((_, b)) if b >= q => best,
+                    _ => Some((offer, q)),
+                },
+            )
+            .map(|(offer, _)| offer)
+    }
+}
 #[derive(Debug, Clone, PartialEq)]
 pub enum ContentType {
     TextPlain,
     OctetStream,
+    TextHtml,
+    Json,
+    EventStream,
 }
 impl ContentType {
     pub fn from2(s: &str) -> Result<ContentType> {
         match s {
             "text/plain" => Ok(ContentType::TextPlain),
             "application/octet-stream" => Ok(ContentType::OctetStream),
+            "text/html" => Ok(ContentType::TextHtml),
+            "application/json" => Ok(ContentType::Json),
+            "text/event-stream" => Ok(ContentType::EventStream),
             ss => Err(Error::GeneralError(format!(
                 "not able to reate ContentType from {}",
                 ss
             ))),
         }
     }
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentType::TextPlain => "text/plain",
+            ContentType::OctetStream => "application/octet-stream",
+            ContentType::TextHtml => "text/html; charset=utf-8",
+            ContentType::Json => "application/json",
+            ContentType::EventStream => "text/event-stream",
+        }
+    }
 }
 impl From<ContentType> for Vec<u8> {
     fn from(value: ContentType) -> Self {
-        match value {
-            ContentType::TextPlain => b"text/plain".to_vec(),
-            ContentType::OctetStream => b"application/octet-stream".to_vec(),
-        }
+        value.as_str().as_bytes().to_vec()
     }
 }
 #[derive(Debug, Clone, From, Deref, Copy, PartialEq)]
-pub struct ContentLength(u32);
+pub struct ContentLength(u64);
+
+/// What a client waits for before sending the body.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expect {
+    /// `100-continue`: an interim 100 response.
+    Continue,
+    /// Nothing the server knows how to meet: 417.
+    Other(String),
+}
+
+/// How a body is framed on an HTTP/1.1 connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferEncoding {
+    Chunked,
+}
 
 #[derive(Debug, Clone, From, Copy, PartialEq)]
 pub enum Encoding {
@@ -117,6 +296,287 @@ pub struct ContentEncoding(Encoding);
 #[derive(Debug, Clone, From, Copy, PartialEq)]
 pub enum Connection {
     Close,
+    Upgrade,
+}
+
+/// A timestamp as carried by `Last-Modified`, `If-Modified-Since` and friends.
+/// HTTP dates have one second resolution, so anything finer is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HttpDate(u64);
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+impl HttpDate {
+    pub fn now() -> HttpDate {
+        SystemTime::now().into()
+    }
+    pub fn timestamp(&self) -> u64 {
+        self.0
+    }
+    /// Accepts the three formats a recipient must understand (RFC 9110, 5.6.7):
+    /// `Sun, 06 Nov 1994 08:49:37 GMT`, `Sunday, 06-Nov-94 08:49:37 GMT`
+    /// and asctime's `Sun Nov  6 08:49:37 1994`.
+    pub fn parse(value: &str) -> Result<HttpDate> {
+        let invalid = || GeneralError(format!("Invalid HTTP date {}", value));
+        let fields: Vec<&str> = value.split_whitespace().collect();
+        let (year, month, day, time) = match fields.as_slice() {
+            [_, day, month, year, time, "GMT"] => (
+                year.parse::<i64>().ok(),
+                *month,
+                day.parse::<u32>().ok(),
+                *time,
+            ),
+            [_, date, time, "GMT"] => match date.split('-').collect::<Vec<_>>().as_slice() {
+                [day, month, year] if year.len() == 2 => (
+                    year.parse::<i64>()
+                        .ok()
+                        .map(|y| if y < 70 { 2000 + y } else { 1900 + y }),
+                    *month,
+                    day.parse::<u32>().ok(),
+                    *time,
+                ),
+                _ => return Err(invalid()),
+            },
+            [_, month, day, time, year] => (
+                year.parse::<i64>().ok(),
+                *month,
+                day.parse::<u32>().ok(),
+                *time,
+            ),
+            _ => return Err(invalid()),
+        };
+        let month = MONTHS.iter().position(|m| *m == month);
+        let clock: Vec<Option<u64>> = time.split(':').map(|v| v.parse().ok()).collect();
+        match (year, month, day, clock.as_slice()) {
+            (Some(y), Some(m), Some(d), [Some(hh), Some(mm), Some(ss)])
+                if y >= 1970 && (1..=31).contains(&d) && *hh < 24 && *mm < 60 && *ss < 61 =>
+            {
+                let days = days_from_civil(y, m as u32 + 1, d) as u64;
+                Ok(HttpDate(days * 86400 + hh * 3600 + mm * 60 + ss))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl From<SystemTime> for HttpDate {
+    fn from(value: SystemTime) -> Self {
+        HttpDate(
+            value
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        )
+    }
+}
+
+impl From<HttpDate> for SystemTime {
+    fn from(value: HttpDate) -> Self {
+        UNIX_EPOCH + Duration::from_secs(value.0)
+    }
+}
+
+impl Display for HttpDate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let days = (self.0 / 86400) as i64;
+        let secs = self.0 % 86400;
+        let (year, month, day) = civil_from_days(days);
+        write!(
+            f,
+            "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            WEEKDAYS[((days + 4) % 7) as usize],
+            day,
+            MONTHS[month as usize - 1],
+            year,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        )
+    }
+}
+
+// Day counting from http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// An entity tag, `"xyzzy"` or `W/"xyzzy"`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ETag {
+    weak: bool,
+    tag: String,
+}
+
+impl ETag {
+    pub fn strong(tag: &str) -> Self {
+        ETag {
+            weak: false,
+            tag: tag.to_string(),
+        }
+    }
+    pub fn weak(tag: &str) -> Self {
+        ETag {
+            weak: true,
+            tag: tag.to_string(),
+        }
+    }
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+    pub fn weaken(self) -> Self {
+        ETag { weak: true, ..self }
+    }
+    /// Both tags are strong and identical; what `If-Match` and `If-Range` use.
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+    /// Identical opaque tags regardless of weakness; what `If-None-Match` uses.
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.tag == other.tag
+    }
+    pub fn parse(value: &str) -> Result<ETag> {
+        let value = value.trim();
+        let (weak, quoted) = match value.strip_prefix("W/") {
+            Some(v) => (true, v),
+            None => (false, value),
+        };
+        match quoted
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .filter(|v| !v.contains('"'))
+        {
+            Some(tag) => Ok(ETag {
+                weak,
+                tag: tag.to_string(),
+            }),
+            None => Err(GeneralError(format!("Invalid entity tag {}", value))),
+        }
+    }
+}
+
+impl Display for ETag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.weak {
+            write!(f, "W/\"{}\"", self.tag)
+        } else {
+            write!(f, "\"{}\"", self.tag)
+        }
+    }
+}
+
+/// The value of `If-Match` and `If-None-Match`: either `*` or a list of tags.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntityTags {
+    Any,
+    Tags(Vec<ETag>),
+}
+
+impl EntityTags {
+    pub fn parse(value: &str) -> Result<EntityTags> {
+        if value.trim() == "*" {
+            return Ok(EntityTags::Any);
+        }
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(ETag::parse)
+            .collect::<Result<Vec<_>>>()
+            .map(EntityTags::Tags)
+    }
+    pub fn matches(&self, etag: Option<&ETag>, eq: impl Fn(&ETag, &ETag) -> bool) -> bool {
+        match (self, etag) {
+            (EntityTags::Any, Some(_)) => true,
+            (EntityTags::Tags(tags), Some(etag)) => tags.iter().any(|t| eq(t, etag)),
+            (_, None) => false,
+        }
+    }
+}
+
+impl Display for EntityTags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EntityTags::Any => write!(f, "*"),
+            EntityTags::Tags(tags) => write!(
+                f,
+                "{}",
+                tags.iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, From, Deref, Copy, PartialEq)]
+pub struct LastModified(HttpDate);
+#[derive(Debug, Clone, From, Deref, PartialEq)]
+pub struct IfNoneMatch(EntityTags);
+#[derive(Debug, Clone, From, Deref, Copy, PartialEq)]
+pub struct IfModifiedSince(HttpDate);
+#[derive(Debug, Clone, From, Deref, PartialEq)]
+pub struct IfMatch(EntityTags);
+#[derive(Debug, Clone, From, Deref, Copy, PartialEq)]
+pub struct IfUnmodifiedSince(HttpDate);
+/// Delay in seconds.
+#[derive(Debug, Clone, From, Deref, Copy, PartialEq)]
+pub struct RetryAfter(u64);
+/// The protocol a client would rather speak, as it named it.
+#[derive(Debug, Clone, From, Deref, PartialEq)]
+pub struct Upgrade(pub String);
+#[derive(Debug, Clone, From, Deref, PartialEq)]
+pub struct CacheControl(pub String);
+/// One link with its parameters, e.g. `</app.css>; rel=preload; as=style`.
+#[derive(Debug, Clone, From, Deref, PartialEq)]
+pub struct Link(pub String);
+/// The last event an event stream client saw before reconnecting.
+#[derive(Debug, Clone, From, Deref, PartialEq)]
+pub struct LastEventId(pub String);
+#[derive(Debug, Clone, From, Deref, PartialEq)]
+pub struct WebSocketKey(pub String);
+#[derive(Debug, Clone, From, Deref, PartialEq)]
+pub struct WebSocketVersion(pub String);
+/// Extensions with their parameters, as offered or agreed to.
+#[derive(Debug, Clone, From, Deref, PartialEq)]
+pub struct WebSocketExtensions(pub String);
+#[derive(Debug, Clone, From, Deref, PartialEq)]
+pub struct WebSocketAccept(pub String);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IfRange {
+    ETag(ETag),
+    Date(HttpDate),
+}
+
+impl IfRange {
+    pub fn parse(value: &str) -> Result<IfRange> {
+        ETag::parse(value)
+            .map(IfRange::ETag)
+            .or_else(|_| HttpDate::parse(value).map(IfRange::Date))
+    }
 }
 #[derive(Debug, Clone, From, PartialEq)]
 #[from(forward)]
@@ -129,6 +589,24 @@ pub enum Header {
     AcceptEncoding(AcceptEncoding),
     ContentEncoding(ContentEncoding),
     Connection(Connection),
+    ETag(ETag),
+    LastModified(LastModified),
+    IfNoneMatch(IfNoneMatch),
+    IfModifiedSince(IfModifiedSince),
+    IfRange(IfRange),
+    IfMatch(IfMatch),
+    IfUnmodifiedSince(IfUnmodifiedSince),
+    RetryAfter(RetryAfter),
+    Upgrade(Upgrade),
+    SecWebSocketKey(WebSocketKey),
+    SecWebSocketVersion(WebSocketVersion),
+    SecWebSocketExtensions(WebSocketExtensions),
+    SecWebSocketAccept(WebSocketAccept),
+    TransferEncoding(TransferEncoding),
+    CacheControl(CacheControl),
+    LastEventId(LastEventId),
+    Expect(Expect),
+    Link(Link),
 }
 impl Header {
     pub fn host(value: &str) -> Self {
@@ -143,7 +621,7 @@ impl Header {
     pub fn content_type(value: ContentType) -> Self {
         Self::ContentType(value)
     }
-    pub fn content_length(value: u32) -> Self {
+    pub fn content_length(value: u64) -> Self {
         Self::ContentLength(ContentLength(value))
     }
     pub fn accept_encoding(value: &[Encoding]) -> Self {
@@ -155,49 +633,144 @@ impl Header {
     pub fn connection(value: Connection) -> Self {
         Self::Connection(value)
     }
+    pub fn etag(value: ETag) -> Self {
+        Self::ETag(value)
+    }
+    pub fn last_modified(value: HttpDate) -> Self {
+        Self::LastModified(LastModified(value))
+    }
+    pub fn if_none_match(value: EntityTags) -> Self {
+        Self::IfNoneMatch(IfNoneMatch(value))
+    }
+    pub fn if_modified_since(value: HttpDate) -> Self {
+        Self::IfModifiedSince(IfModifiedSince(value))
+    }
+    pub fn if_range(value: IfRange) -> Self {
+        Self::IfRange(value)
+    }
+    pub fn if_match(value: EntityTags) -> Self {
+        Self::IfMatch(IfMatch(value))
+    }
+    pub fn if_unmodified_since(value: HttpDate) -> Self {
+        Self::IfUnmodifiedSince(IfUnmodifiedSince(value))
+    }
+    /// Rounded up to whole seconds.
+    pub fn retry_after(delay: Duration) -> Self {
+        let secs = delay.as_secs() + (delay.subsec_nanos() > 0) as u64;
+        Self::RetryAfter(RetryAfter(secs))
+    }
+    pub fn upgrade(value: &str) -> Self {
+        Self::Upgrade(Upgrade(value.to_string()))
+    }
+    pub fn sec_websocket_key(value: &str) -> Self {
+        Self::SecWebSocketKey(WebSocketKey(value.to_string()))
+    }
+    pub fn sec_websocket_version(value: &str) -> Self {
+        Self::SecWebSocketVersion(WebSocketVersion(value.to_string()))
+    }
+    pub fn sec_websocket_extensions(value: &str) -> Self {
+        Self::SecWebSocketExtensions(WebSocketExtensions(value.to_string()))
+    }
+    pub fn sec_websocket_accept(value: &str) -> Self {
+        Self::SecWebSocketAccept(WebSocketAccept(value.to_string()))
+    }
+    pub fn transfer_encoding(value: TransferEncoding) -> Self {
+        Self::TransferEncoding(value)
+    }
+    pub fn cache_control(value: &str) -> Self {
+        Self::CacheControl(CacheControl(value.to_string()))
+    }
+    pub fn last_event_id(value: &str) -> Self {
+        Self::LastEventId(LastEventId(value.to_string()))
+    }
+    pub fn expect(value: Expect) -> Self {
+        Self::Expect(value)
+    }
+    pub fn link(value: &str) -> Self {
+        Self::Link(Link(value.to_string()))
+    }
 }
-impl From<Header> for Vec<u8> {
-    fn from(value: Header) -> Self {
-        match value {
-            Header::Host(host) => format!("Host: {:?}", host.0).as_bytes().to_vec(),
-            Header::UserAgent(agent) => format!("User-Agent: {:?}", agent.0).as_bytes().to_vec(),
-            Header::Accept(accept) => format!("Accept: {:?}", accept.0).as_bytes().to_vec(),
-            Header::ContentType(ct) => {
-                let mut r: Vec<u8> = b"Content-Type: ".to_vec();
-                let ctv: Vec<u8> = ct.into();
-                r.extend(ctv);
-                r
-            }
-            Header::ContentLength(cl) => format!("Content-Length:{:?}", cl.0).as_bytes().to_vec(),
+impl Display for Header {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Header::Host(host) => write!(f, "Host: {:?}", host.0),
+            Header::UserAgent(agent) => write!(f, "User-Agent: {:?}", agent.0),
+            Header::Accept(accept) => write!(f, "Accept: {:?}", accept.0),
+            Header::ContentType(ct) => write!(f, "Content-Type: {}", ct.as_str()),
+            Header::ContentLength(cl) => write!(f, "Content-Length:{:?}", cl.0),
             Header::AcceptEncoding(AcceptEncoding(encodings)) => {
                 let enc: String = encodings
-                    .into_iter()
+                    .iter()
                     .map(|enc| match enc {
                         Encoding::Gzip => "gzip",
                     })
                     .collect::<Vec<_>>()
                     .join(", ");
-                format!("Accept-Encoding:{}", enc).as_bytes().to_vec()
+                write!(f, "Accept-Encoding:{}", enc)
             }
             Header::ContentEncoding(encoding) => {
                 let enc = match encoding.0 {
                     Encoding::Gzip => "gzip",
                 };
-                format!("Content-Encoding:{}", enc).as_bytes().to_vec()
+                write!(f, "Content-Encoding:{}", enc)
             }
             Header::Connection(c) => {
                 let action = match c {
                     Connection::Close => "close",
+                    Connection::Upgrade => "Upgrade",
                 };
-                format!("Connection:{}", action).as_bytes().to_vec()
+                write!(f, "Connection:{}", action)
             }
+            Header::ETag(etag) => write!(f, "ETag: {}", etag),
+            Header::LastModified(date) => write!(f, "Last-Modified: {}", date.0),
+            Header::IfNoneMatch(tags) => write!(f, "If-None-Match: {}", tags.0),
+            Header::IfModifiedSince(date) => {
+                write!(f, "If-Modified-Since: {}", date.0)
+            }
+            Header::IfRange(IfRange::ETag(etag)) => {
+                write!(f, "If-Range: {}", etag)
+            }
+            Header::IfRange(IfRange::Date(date)) => {
+                write!(f, "If-Range: {}", date)
+            }
+            Header::IfMatch(tags) => write!(f, "If-Match: {}", tags.0),
+            Header::IfUnmodifiedSince(date) => write!(f, "If-Unmodified-Since: {}", date.0),
+            Header::RetryAfter(secs) => write!(f, "Retry-After: {}", secs.0),
+            Header::Upgrade(protocol) => write!(f, "Upgrade: {}", protocol.0),
+            Header::SecWebSocketKey(key) => write!(f, "Sec-WebSocket-Key: {}", key.0),
+            Header::SecWebSocketVersion(version) => {
+                write!(f, "Sec-WebSocket-Version: {}", version.0)
+            }
+            Header::SecWebSocketExtensions(extensions) => {
+                write!(f, "Sec-WebSocket-Extensions: {}", extensions.0)
+            }
+            Header::SecWebSocketAccept(accept) => write!(f, "Sec-WebSocket-Accept: {}", accept.0),
+            Header::TransferEncoding(TransferEncoding::Chunked) => {
+                write!(f, "Transfer-Encoding: chunked")
+            }
+            Header::CacheControl(directives) => write!(f, "Cache-Control: {}", directives.0),
+            Header::LastEventId(id) => write!(f, "Last-Event-ID: {}", id.0),
+            Header::Expect(Expect::Continue) => write!(f, "Expect: 100-continue"),
+            Header::Expect(Expect::Other(expectation)) => write!(f, "Expect: {}", expectation),
+            Header::Link(link) => write!(f, "Link: {}", link.0),
         }
     }
 }
+impl From<Header> for Vec<u8> {
+    fn from(value: Header) -> Self {
+        value.to_string().into_bytes()
+    }
+}
 
 #[derive(From, Debug, Clone, Deref)]
 pub struct Headers(Vec<Header>);
 impl Headers {
+    pub fn accept(&self) -> Option<Accept> {
+        self.iter().find_map(|v| match v {
+            Header::Accept(v) => Some(v.clone()),
+            _ => None,
+        })
+    }
     pub fn content_length(&self) -> Option<ContentLength> {
         self.clone().0.into_iter().find_map(|v| match v {
             Header::ContentLength(cl) => Some(cl),
@@ -222,19 +795,113 @@ impl Headers {
             _ => None,
         })
     }
+    pub fn if_none_match(&self) -> Option<EntityTags> {
+        self.iter().find_map(|v| match v {
+            Header::IfNoneMatch(v) => Some(v.0.clone()),
+            _ => None,
+        })
+    }
+    pub fn if_modified_since(&self) -> Option<HttpDate> {
+        self.iter().find_map(|v| match v {
+            Header::IfModifiedSince(v) => Some(v.0),
+            _ => None,
+        })
+    }
+    pub fn host(&self) -> Option<Host> {
+        self.iter().find_map(|v| match v {
+            Header::Host(v) => Some(v.clone()),
+            _ => None,
+        })
+    }
+    pub fn upgrade(&self) -> Option<Upgrade> {
+        self.iter().find_map(|v| match v {
+            Header::Upgrade(v) => Some(v.clone()),
+            _ => None,
+        })
+    }
+    pub fn sec_websocket_key(&self) -> Option<WebSocketKey> {
+        self.iter().find_map(|v| match v {
+            Header::SecWebSocketKey(v) => Some(v.clone()),
+            _ => None,
+        })
+    }
+    pub fn sec_websocket_version(&self) -> Option<WebSocketVersion> {
+        self.iter().find_map(|v| match v {
+            Header::SecWebSocketVersion(v) => Some(v.clone()),
+            _ => None,
+        })
+    }
+    pub fn sec_websocket_extensions(&self) -> Option<WebSocketExtensions> {
+        self.iter().find_map(|v| match v {
+            Header::SecWebSocketExtensions(v) => Some(v.clone()),
+            _ => None,
+        })
+    }
+    pub fn expect(&self) -> Option<Expect> {
+        self.iter().find_map(|v| match v {
+            Header::Expect(v) => Some(v.clone()),
+            _ => None,
+        })
+    }
+    pub fn last_event_id(&self) -> Option<LastEventId> {
+        self.iter().find_map(|v| match v {
+            Header::LastEventId(v) => Some(v.clone()),
+            _ => None,
+        })
+    }
+    pub fn if_range(&self) -> Option<IfRange> {
+        self.iter().find_map(|v| match v {
+            Header::IfRange(v) => Some(v.clone()),
+            _ => None,
+        })
+    }
+    pub fn if_match(&self) -> Option<EntityTags> {
+        self.iter().find_map(|v| match v {
+            Header::IfMatch(v) => Some(v.0.clone()),
+            _ => None,
+        })
+    }
+    pub fn if_unmodified_since(&self) -> Option<HttpDate> {
+        self.iter().find_map(|v| match v {
+            Header::IfUnmodifiedSince(v) => Some(v.0),
+            _ => None,
+        })
+    }
 }
 #[derive(Debug, Clone)]
-pub struct ResponseBody(pub Bytes);
-
-impl From<ResponseBody> for Bytes {
-    fn from(value: ResponseBody) -> Self {
-        value.0
-    }
+pub enum ResponseBody {
+    Full(Bytes),
+    /// Left on disk until the connection sends it, see `send_file`.
+    File(FileBody),
+    /// Made while it is sent.
+    Stream(BodyStream),
 }
 
-impl<'a> From<&'a ResponseBody> for &'a [u8] {
-    fn from(value: &'a ResponseBody) -> Self {
-        value.0.as_ref()
+impl ResponseBody {
+    /// 0 for a stream, which is not known until it ends.
+    pub fn len(&self) -> u64 {
+        match self {
+            ResponseBody::Full(b) => b.len() as u64,
+            ResponseBody::File(f) => f.len(),
+            ResponseBody::Stream(_) => 0,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        !self.is_stream() && self.len() == 0
+    }
+    pub fn is_stream(&self) -> bool {
+        matches!(self, ResponseBody::Stream(_))
+    }
+    /// The whole body in memory, reading it from disk if need be. A stream
+    /// only exists while it is sent.
+    pub fn bytes(&self) -> Result<Bytes> {
+        match self {
+            ResponseBody::Full(b) => Ok(b.clone()),
+            ResponseBody::File(f) => f.read(),
+            ResponseBody::Stream(_) => {
+                Err(GeneralError("A streamed body is not in memory".to_string()))
+            }
+        }
     }
 }
 
@@ -242,7 +909,7 @@ impl TryInto<ResponseBody> for Bytes {
     type Error = Error;
 
     fn try_into(self) -> Result<ResponseBody> {
-        Ok(ResponseBody(self))
+        Ok(ResponseBody::Full(self))
     }
 }
 #[derive(Debug, Clone, Copy)]
@@ -259,6 +926,44 @@ impl StatusLine {
             Some(Reason::Created),
         )
     }
+    pub fn no_content() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC204,
+            Some(Reason::NoContent),
+        )
+    }
+    pub fn bad_request() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC400,
+            Some(Reason::BadRequest),
+        )
+    }
+    pub fn conflict() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC409,
+            Some(Reason::Conflict),
+        )
+    }
+    pub fn internal_server_error() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC500,
+            Some(Reason::InternalServerError),
+        )
+    }
+    pub fn status_code(&self) -> StatusCode {
+        self.1
+    }
+    pub fn not_modified() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC304,
+            Some(Reason::NotModified),
+        )
+    }
     pub fn not_found() -> StatusLine {
         Self(
             HttpVersion::HttpOne,
@@ -266,6 +971,118 @@ impl StatusLine {
             Some(Reason::NotFound),
         )
     }
+    pub fn precondition_failed() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC412,
+            Some(Reason::PreconditionFailed),
+        )
+    }
+    pub fn content_too_large() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC413,
+            Some(Reason::ContentTooLarge),
+        )
+    }
+    pub fn uri_too_long() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC414,
+            Some(Reason::UriTooLong),
+        )
+    }
+    pub fn request_header_fields_too_large() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC431,
+            Some(Reason::RequestHeaderFieldsTooLarge),
+        )
+    }
+    pub fn request_timeout() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC408,
+            Some(Reason::RequestTimeout),
+        )
+    }
+    pub fn service_unavailable() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC503,
+            Some(Reason::ServiceUnavailable),
+        )
+    }
+    pub fn switching_protocols() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC101,
+            Some(Reason::SwitchingProtocols),
+        )
+    }
+    pub fn upgrade_required() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC426,
+            Some(Reason::UpgradeRequired),
+        )
+    }
+    pub fn not_implemented() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC501,
+            Some(Reason::NotImplemented),
+        )
+    }
+    pub fn continue_() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC100,
+            Some(Reason::Continue),
+        )
+    }
+    pub fn expectation_failed() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC417,
+            Some(Reason::ExpectationFailed),
+        )
+    }
+    pub fn early_hints() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC103,
+            Some(Reason::EarlyHints),
+        )
+    }
+    pub fn forbidden() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC403,
+            Some(Reason::Forbidden),
+        )
+    }
+    pub fn bad_gateway() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC502,
+            Some(Reason::BadGateway),
+        )
+    }
+    pub fn gateway_timeout() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC504,
+            Some(Reason::GatewayTimeout),
+        )
+    }
+    pub fn length_required() -> StatusLine {
+        Self(
+            HttpVersion::HttpOne,
+            StatusCode::SC411,
+            Some(Reason::LengthRequired),
+        )
+    }
 }
 
 impl From<StatusLine> for Vec<u8> {
@@ -283,8 +1100,18 @@ impl From<StatusLine> for Vec<u8> {
     }
 }
 
+/// A 1xx response sent ahead of the final one, such as 103 Early Hints.
+#[derive(Debug, Clone)]
+pub struct Interim(pub StatusLine, pub Vec<Header>);
+
+/// Status, headers, body, and the interim responses that go before it.
 #[derive(Debug, Clone)]
-pub struct Response(pub StatusLine, pub Vec<Header>, pub Option<ResponseBody>);
+pub struct Response(
+    pub StatusLine,
+    pub Vec<Header>,
+    pub Option<ResponseBody>,
+    pub Vec<Interim>,
+);
 
 impl Response {
     pub fn ok(body: &str) -> Result<Self> {
@@ -292,9 +1119,10 @@ impl Response {
             StatusLine::ok(),
             vec![
                 Header::ContentType(ContentType::TextPlain),
-                Header::ContentLength(ContentLength(body.len() as u32)),
+                Header::ContentLength(ContentLength(body.len() as u64)),
             ],
-            Some(ResponseBody(Bytes::from(body.as_bytes().to_vec()))),
+            Some(ResponseBody::Full(Bytes::from(body.as_bytes().to_vec()))),
+            vec![],
         ))
     }
     pub fn ok_bin(body: &[u8]) -> Result<Self> {
@@ -302,17 +1130,58 @@ impl Response {
             StatusLine::ok(),
             vec![
                 Header::ContentType(ContentType::OctetStream),
-                Header::ContentLength(ContentLength(body.len() as u32)),
+                Header::ContentLength(ContentLength(body.len() as u64)),
             ],
-            Some(ResponseBody(Bytes::from(body.to_vec()))),
+            Some(ResponseBody::Full(Bytes::from(body.to_vec()))),
+            vec![],
         ))
     }
+    pub fn file(body: FileBody) -> Self {
+        Response(
+            StatusLine::ok(),
+            vec![
+                Header::ContentType(ContentType::OctetStream),
+                Header::ContentLength(ContentLength(body.len())),
+            ],
+            Some(ResponseBody::File(body)),
+            vec![],
+        )
+    }
+    /// A body sent as `body` makes it, of unknown length.
+    pub fn stream(content_type: ContentType, body: BodyStream) -> Self {
+        Response(
+            StatusLine::ok(),
+            vec![Header::ContentType(content_type)],
+            Some(ResponseBody::Stream(body)),
+            vec![],
+        )
+    }
     pub fn add_header(&mut self, header: Header) -> &Self {
         self.1.push(header);
         self
     }
+    pub fn add_interim(&mut self, interim: Interim) -> &Self {
+        self.3.push(interim);
+        self
+    }
+    /// Replaces any header of the same kind, or adds it.
+    pub fn set_header(&mut self, header: Header) -> &Self {
+        let kind = std::mem::discriminant(&header);
+        self.1.retain(|h| std::mem::discriminant(h) != kind);
+        self.1.push(header);
+        self
+    }
+    /// A transformed body is no longer byte-for-byte the one a strong tag vouched for.
+    pub fn weaken_etag(&mut self) -> &Self {
+        self.1.iter_mut().for_each(|h| {
+            if let Header::ETag(etag) = h {
+                *h = Header::etag(etag.clone().weaken())
+            }
+        });
+        self
+    }
     pub fn set_body(&mut self, f: impl Fn(&ResponseBody) -> Result<ResponseBody>) -> Result<&Self> {
-        let Response(_, headers, body) = self;
+        let Response(_, headers, body, _) = self;
         match body {
             None => Ok(self),
             Some(b) => {
@@ -320,7 +1189,7 @@ impl Response {
                 let headers = headers
                     .iter()
                     .map(|h| match h {
-                        Header::ContentLength(_) => Header::content_length(rb.0.len() as u32),
+                        Header::ContentLength(_) => Header::content_length(rb.len()),
                         h => h.clone(),
                     })
                     .collect();
@@ -333,31 +1202,51 @@ impl Response {
 }
 const CRLF: &[u8; 2] = b"\r\n";
 const SPACE: &[u8; 1] = b" ";
-impl From<Response> for Vec<u8> {
-    fn from(value: Response) -> Self {
-        let Response(status_line, headers, body) = value;
-        let mut result: Vec<u8> = vec![];
-        result.extend::<Vec<u8>>(status_line.into());
-        result.extend(CRLF);
-
-        let headers_b: Vec<u8> = headers.into_iter().rfold(vec![], |mut v: Vec<u8>, el| {
-            let el: Vec<u8> = el.into();
-            v.extend(el);
-            v.extend(CRLF);
-            v
-        });
-        if headers_b.is_empty() {
-            result.extend(CRLF);
-        } else {
-            result.extend(headers_b);
-            result.extend(CRLF);
+impl Response {
+    /// Writes the interim responses, then the status line and headers, up
+    /// to and including the empty line, into `buf`. It is cleared first, so
+    /// a connection can keep reusing one buffer.
+    pub fn write_head(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        for Interim(status_line, headers) in &self.3 {
+            write_head(buf, status_line, headers);
         }
+        write_head(buf, &self.0, &self.1);
+    }
+    pub fn head(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        self.write_head(&mut buf);
+        buf
+    }
+}
 
-        body.into_iter().for_each(|b| {
-            let v: Bytes = b.into();
-            result.extend(v.to_vec())
-        });
+fn write_head(buf: &mut Vec<u8>, status_line: &StatusLine, headers: &[Header]) {
+    let StatusLine(http_version, status_code, reason) = status_line;
+    buf.extend_from_slice(http_version.as_bytes());
+    buf.extend_from_slice(SPACE);
+    buf.extend_from_slice(status_code.as_bytes());
+    buf.extend_from_slice(SPACE);
+    if let Some(reason) = reason {
+        buf.extend_from_slice(reason.as_bytes());
+    }
+    buf.extend_from_slice(CRLF);
+    for header in headers.iter().rev() {
+        // Writing into a Vec cannot fail.
+        let _ = write!(buf, "{}", header);
+        buf.extend_from_slice(CRLF);
+    }
+    buf.extend_from_slice(CRLF);
+}
 
+/// Serializes everything in memory; a file body that can no longer be read
+/// ends up empty. The server sends file bodies with `send_file` instead.
+impl From<Response> for Vec<u8> {
+    fn from(value: Response) -> Self {
+        let mut result = value.head();
+        value
+            .2
+            .into_iter()
+            .for_each(|b| result.extend(b.bytes().unwrap_or_default().to_vec()));
         result
     }
 }
@@ -366,19 +1255,105 @@ impl From<Response> for Vec<u8> {
 pub enum HttpMethod {
     Get,
     Post,
+    Put,
+    Delete,
+    Head,
+    /// Asks for a tunnel to the authority in the target.
+    Connect,
 }
 
 impl HttpMethod {
-    pub fn is_get(&self) -> bool {
+    pub fn as_str(&self) -> &'static str {
         match self {
-            HttpMethod::Get => true,
-            _ => false,
+            HttpMethod::Get => "GET",
+            HttpMethod::Post => "POST",
+            HttpMethod::Put => "PUT",
+            HttpMethod::Delete => "DELETE",
+            HttpMethod::Head => "HEAD",
+            HttpMethod::Connect => "CONNECT",
         }
     }
+    pub fn is_get(&self) -> bool {
+        matches!(self, HttpMethod::Get)
+    }
     pub fn is_post(&self) -> bool {
-        match self {
-            HttpMethod::Post => true,
-            _ => false,
-        }
+        matches!(self, HttpMethod::Post)
+    }
+    pub fn is_put(&self) -> bool {
+        matches!(self, HttpMethod::Put)
+    }
+    pub fn is_delete(&self) -> bool {
+        matches!(self, HttpMethod::Delete)
+    }
+    pub fn is_head(&self) -> bool {
+        matches!(self, HttpMethod::Head)
+    }
+    pub fn is_connect(&self) -> bool {
+        matches!(self, HttpMethod::Connect)
+    }
+    /// HEAD is answered like GET, minus the body.
+    pub fn is_get_or_head(&self) -> bool {
+        self.is_get() || self.is_head()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_head() -> Result<()> {
+        let resp = Response::ok("hello")?;
+        let mut buf = b"left over from the previous response".to_vec();
+        resp.write_head(&mut buf);
+        assert_eq!(
+            buf,
+            b"HTTP/1.1 200 OK\r\nContent-Length:5\r\nContent-Type: text/plain\r\n\r\n"
+        );
+        buf.extend_from_slice(b"hello");
+        assert_eq!(buf, Vec::<u8>::from(resp));
+        Ok(())
+    }
+
+    #[test]
+    fn test_http_date_formats() -> Result<()> {
+        let imf = HttpDate::parse("Sun, 06 Nov 1994 08:49:37 GMT")?;
+        assert_eq!(imf, HttpDate::parse("Sunday, 06-Nov-94 08:49:37 GMT")?);
+        assert_eq!(imf, HttpDate::parse("Sun Nov  6 08:49:37 1994")?);
+        assert_eq!(imf.to_string(), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            HttpDate(1_709_210_096).to_string(),
+            "Thu, 29 Feb 2024 12:34:56 GMT"
+        );
+        assert!(HttpDate::parse("Sun, 06 Nov 1994 25:49:37 GMT").is_err());
+        assert!(HttpDate::parse("yesterday").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_entity_tags() -> Result<()> {
+        assert_eq!(EntityTags::parse(" * ")?, EntityTags::Any);
+        let tags = EntityTags::parse("\"a\", W/\"b\"")?;
+        assert_eq!(tags.to_string(), "\"a\", W/\"b\"");
+        assert!(tags.matches(Some(&ETag::strong("b")), ETag::weak_eq));
+        assert!(!tags.matches(Some(&ETag::strong("b")), ETag::strong_eq));
+        assert!(EntityTags::parse("a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_prefers() {
+        let offers = ["application/json", "text/html"];
+        let accept = |v: &str| Accept(v.to_string()).prefers(&offers);
+        assert_eq!(accept("*/*"), Some("application/json"));
+        assert_eq!(
+            accept("text/html,application/xhtml+xml,*/*;q=0.8"),
+            Some("text/html")
+        );
+        assert_eq!(
+            accept("text/*;q=0.5, application/json;q=0.1"),
+            Some("text/html")
+        );
+        assert_eq!(accept("image/png"), None);
     }
 }
@@ -0,0 +1,292 @@
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    mk_response, request, BoxFuture, Connection, Context, Endpoint, Header, Request, Response,
+    Result, Rewind, StatusCode, StatusLine, Switch,
+};
+
+/// Any connection the server can hand over.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// A connection that stopped speaking HTTP/1.1 after the response to
+/// `request`. Bytes the client sent behind the request head are read first.
+/// Like any connection it is cut when the server stops and `drain_timeout`
+/// runs out.
+pub struct Upgraded {
+    request: Arc<Request>,
+    stream: Rewind<Box<dyn Io>>,
+}
+
+impl Upgraded {
+    pub fn new(request: Arc<Request>, buffered: Bytes, stream: Box<dyn Io>) -> Self {
+        Upgraded {
+            request,
+            stream: Rewind::new(buffered, stream),
+        }
+    }
+    /// The request that asked for the switch.
+    pub fn request(&self) -> &Arc<Request> {
+        &self.request
+    }
+    /// Sends the response to the request, for an `UpgradeHandler::answering`.
+    pub async fn respond(&mut self, resp: Response) -> Result<()> {
+        let resp: Vec<u8> = resp.into();
+        self.write_all(&resp).await.context("Write response")?;
+        self.flush().await.context("Flushing the response")
+    }
+    /// The bytes already read from the connection and the connection, for
+    /// code that takes the stream as it is.
+    pub fn into_parts(self) -> (Bytes, Box<dyn Io>) {
+        self.stream.into_parts()
+    }
+}
+
+impl Debug for Upgraded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Upgraded")
+            .field("request", &self.request)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for Upgraded {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Upgraded {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+type UpgradeHandlerFn = dyn Fn(Upgraded) -> BoxFuture<'static, Result<()>> + Send + Sync;
+
+/// Takes over a connection once the response agreed to switch protocols.
+#[derive(Clone)]
+pub struct UpgradeHandler {
+    handler: Arc<UpgradeHandlerFn>,
+    answers: bool,
+}
+
+impl UpgradeHandler {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(Upgraded) -> BoxFuture<'static, Result<()>> + Send + Sync + 'static,
+    {
+        UpgradeHandler {
+            handler: Arc::new(f),
+            answers: false,
+        }
+    }
+    /// For a switch that can only be agreed to after some waiting, like a
+    /// tunnel for its upstream connection. The route's response just lets
+    /// it go ahead; the handler sends the real one with `Upgraded::respond`.
+    pub fn answering<F>(f: F) -> Self
+    where
+        F: Fn(Upgraded) -> BoxFuture<'static, Result<()>> + Send + Sync + 'static,
+    {
+        UpgradeHandler {
+            handler: Arc::new(f),
+            answers: true,
+        }
+    }
+    /// Whether the handler sends the response itself.
+    pub fn answers(&self) -> bool {
+        self.answers
+    }
+    pub async fn handle(&self, stream: Upgraded) -> Result<()> {
+        (self.handler)(stream).await
+    }
+}
+
+impl Debug for UpgradeHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UpgradeHandler")
+    }
+}
+
+/// Switches the connection to `protocol` with a 101 when the client asks
+/// for it, and hands it to `handler`. A request that does not is answered
+/// 426, one with a body 400, as the body would be taken for the protocol's.
+pub fn upgrade<F, Fut>(protocol: &str, handler: F) -> impl Endpoint<Output = Switch>
+where
+    F: Fn(Upgraded) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let protocol = protocol.to_string();
+    let handler = Arc::new(handler);
+    request().map(move |req| {
+        let resp = if !requests_upgrade(&req, &protocol) {
+            let resp = mk_response("", StatusCode::SC426);
+            resp.borrow_mut().add_header(Header::upgrade(&protocol));
+            resp.borrow_mut()
+                .add_header(Header::connection(Connection::Upgrade));
+            resp
+        } else if req.headers.content_length().map_or(0, |l| *l) > 0 {
+            mk_response("", StatusCode::SC400)
+        } else {
+            RefCell::new(Response(
+                StatusLine::switching_protocols(),
+                vec![
+                    Header::connection(Connection::Upgrade),
+                    Header::upgrade(&protocol),
+                ],
+                None,
+                vec![],
+            ))
+        };
+        let handler = Arc::clone(&handler);
+        Switch(
+            resp,
+            UpgradeHandler::new(move |stream| Box::pin(handler(stream))),
+        )
+    })
+}
+
+/// Whether `req` asks to switch to `protocol`, one of those it lists.
+/// HTTP/1.0 has no Upgrade, from such a client it is ignored.
+pub(crate) fn requests_upgrade(req: &Request, protocol: &str) -> bool {
+    let listed = req.headers.upgrade().is_some_and(|v| {
+        v.0.split(',')
+            .any(|offered| offered.trim().eq_ignore_ascii_case(protocol))
+    });
+    listed && req.headers.connection() == Some(Connection::Upgrade) && req.takes_interim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{route, serve_connection, ConnectionInfo, State};
+    use tokio::io::AsyncReadExt;
+
+    async fn read_head(client: &mut tokio::io::DuplexStream) -> Result<String> {
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.context("read")?);
+        }
+        Ok(String::from_utf8_lossy(&head).to_string())
+    }
+
+    #[tokio::test]
+    async fn test_upgrade() -> Result<()> {
+        // Echoes what it reads, starting with what came behind the request.
+        let echo = || {
+            upgrade("echo/1", |stream| async move {
+                let (buffered, mut stream) = stream.into_parts();
+                stream.write_all(&buffered).await.context("write")?;
+                let mut buf = vec![0; 64];
+                loop {
+                    let n = stream.read(&mut buf).await.context("read")?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    stream.write_all(&buf[..n]).await.context("write")?;
+                }
+            })
+        };
+        let f = Arc::new(move |s: State| {
+            let routes = route::get("/").set_switch(echo());
+            std::future::ready(routes.handle(s).map(|(s, _)| s))
+        });
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        let info = ConnectionInfo::new(None, None);
+        tokio::spawn(serve_connection(
+            server,
+            info,
+            f.clone(),
+            Default::default(),
+        ));
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: foo, echo/1\r\n\r\nearly";
+        client
+            .write_all(request.as_bytes())
+            .await
+            .context("write")?;
+        let head = read_head(&mut client).await?;
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Upgrade: echo/1\r\n"));
+        let mut echoed = [0; 5];
+        client.read_exact(&mut echoed).await.context("read")?;
+        assert_eq!(&echoed, b"early");
+        client.write_all(b"later").await.context("write")?;
+        client.read_exact(&mut echoed).await.context("read")?;
+        assert_eq!(&echoed, b"later");
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        let info = ConnectionInfo::new(None, None);
+        tokio::spawn(serve_connection(
+            server,
+            info,
+            f.clone(),
+            Default::default(),
+        ));
+        let request =
+            "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: foo\r\n\r\n";
+        client
+            .write_all(request.as_bytes())
+            .await
+            .context("write")?;
+        let head = read_head(&mut client).await?;
+        assert!(head.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(head.contains("Upgrade: echo/1\r\n"));
+
+        // HTTP/1.0 can not switch, whatever it asks for.
+        let (mut client, server) = tokio::io::duplex(4096);
+        let info = ConnectionInfo::new(None, None);
+        tokio::spawn(serve_connection(server, info, f, Default::default()));
+        let request = "GET / HTTP/1.0\r\nConnection: Upgrade\r\nUpgrade: echo/1\r\n\r\n";
+        client
+            .write_all(request.as_bytes())
+            .await
+            .context("write")?;
+        let head = read_head(&mut client).await?;
+        assert!(head.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        let h2c = |version: &str| {
+            let request = format!(
+                "GET / {version}\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\n\r\n"
+            );
+            crate::parse_request(request.as_bytes()).map(|req| crate::wants_h2c(&req))
+        };
+        assert!(h2c("HTTP/1.1")?);
+        assert!(!h2c("HTTP/1.0")?);
+        Ok(())
+    }
+}
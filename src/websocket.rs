@@ -0,0 +1,783 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::{Buf, Bytes, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    mk_response, request, requests_upgrade, Connection, Context, Endpoint, Error, Header, Request,
+    Response, Result, StatusCode, StatusLine, Switch, UpgradeHandler, Upgraded,
+};
+
+// Appended to the client's key before hashing it, RFC 6455 section 1.3.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const VERSION: &str = "13";
+const DEFLATE: &str = "permessage-deflate";
+// What a sync flush ends a compressed message with; it goes unsent.
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+// Smaller messages would not get any smaller.
+const MIN_COMPRESSED: usize = 64;
+const MAX_CONTROL: usize = 125;
+const READ_CHUNK: usize = 16 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const RSV2_3: u8 = 0x30;
+const MASKED: u8 = 0x80;
+
+/// Why a connection is closed, sent in the close frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CloseCode(pub u16);
+
+impl CloseCode {
+    pub const NORMAL: CloseCode = CloseCode(1000);
+    pub const GOING_AWAY: CloseCode = CloseCode(1001);
+    pub const PROTOCOL_ERROR: CloseCode = CloseCode(1002);
+    pub const UNSUPPORTED_DATA: CloseCode = CloseCode(1003);
+    pub const INVALID_DATA: CloseCode = CloseCode(1007);
+    pub const POLICY_VIOLATION: CloseCode = CloseCode(1008);
+    pub const MESSAGE_TOO_BIG: CloseCode = CloseCode(1009);
+    pub const INTERNAL_ERROR: CloseCode = CloseCode(1011);
+
+    /// Codes a peer may send; the others are reserved or only ever local.
+    fn is_valid(&self) -> bool {
+        matches!(self.0, 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Bytes),
+    /// Already answered with a pong by the time it is received.
+    Ping(Bytes),
+    Pong(Bytes),
+    /// The peer is done; `None` when it gave no code.
+    Close(Option<CloseFrame>),
+}
+
+/// Answers a WebSocket handshake with 101 and runs `handler` on the
+/// connection. A request that is not one is answered 426, one with a
+/// malformed key 400. `permessage-deflate` is agreed to when offered.
+pub fn websocket<F, Fut>(handler: F) -> impl Endpoint<Output = Switch>
+where
+    F: Fn(WebSocket) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let handler = Arc::new(handler);
+    request().map(move |req| {
+        let (resp, deflate) = match handshake(&req) {
+            Ok(agreed) => agreed,
+            Err(refusal) => {
+                return Switch(refusal, UpgradeHandler::new(|_| Box::pin(async { Ok(()) })))
+            }
+        };
+        let handler = Arc::clone(&handler);
+        let upgrade = UpgradeHandler::new(move |stream| {
+            let socket = WebSocket::new(stream, deflate.map(Deflate::new));
+            Box::pin(handler(socket))
+        });
+        Switch(resp, upgrade)
+    })
+}
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    STANDARD.encode(sha1.finalize())
+}
+
+// The switch, with the deflate parameters agreed to, or the refusal.
+fn handshake(
+    req: &Request,
+) -> std::result::Result<(RefCell<Response>, Option<DeflateParams>), RefCell<Response>> {
+    let headers = &req.headers;
+    if !requests_upgrade(req, "websocket") {
+        let resp = mk_response("", StatusCode::SC426);
+        resp.borrow_mut().add_header(Header::upgrade("websocket"));
+        resp.borrow_mut()
+            .add_header(Header::connection(Connection::Upgrade));
+        return Err(resp);
+    }
+    if headers.sec_websocket_version().as_deref().map(|v| v.trim()) != Some(VERSION) {
+        let resp = mk_response("", StatusCode::SC426);
+        resp.borrow_mut()
+            .add_header(Header::sec_websocket_version(VERSION));
+        return Err(resp);
+    }
+    let key = headers.sec_websocket_key();
+    let valid = key
+        .as_ref()
+        .and_then(|key| STANDARD.decode(key.trim()).ok())
+        .is_some_and(|nonce| nonce.len() == 16);
+    let bodiless = headers.content_length().map_or(0, |l| *l) == 0;
+    let key = match key {
+        Some(key) if valid && req.http_method().is_get() && bodiless => key,
+        _ => return Err(mk_response("", StatusCode::SC400)),
+    };
+    let deflate = headers
+        .sec_websocket_extensions()
+        .and_then(|offers| DeflateParams::negotiate(&offers));
+    let mut resp = Response(
+        StatusLine::switching_protocols(),
+        vec![
+            Header::connection(Connection::Upgrade),
+            Header::upgrade("websocket"),
+            Header::sec_websocket_accept(&accept_key(key.trim())),
+        ],
+        None,
+        vec![],
+    );
+    if let Some(params) = &deflate {
+        resp.add_header(Header::sec_websocket_extensions(&params.to_string()));
+    }
+    Ok((RefCell::new(resp), deflate))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DeflateParams {
+    server_no_context_takeover: bool,
+}
+
+impl DeflateParams {
+    /// The first `permessage-deflate` offer this side can honour. Windows
+    /// smaller than zlib's default can not be asked of the compressor.
+    fn negotiate(offers: &str) -> Option<DeflateParams> {
+        offers.split(',').find_map(|offer| {
+            let mut parts = offer.split(';').map(str::trim);
+            if parts.next() != Some(DEFLATE) {
+                return None;
+            }
+            let mut params = DeflateParams {
+                server_no_context_takeover: false,
+            };
+            let mut seen = vec![];
+            for param in parts {
+                let (name, value) = match param.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (param, None),
+                };
+                if seen.contains(&name) {
+                    return None;
+                }
+                seen.push(name);
+                match (name, value) {
+                    ("server_no_context_takeover", None) => {
+                        params.server_no_context_takeover = true
+                    }
+                    ("client_no_context_takeover", None) => {}
+                    ("client_max_window_bits", None) => {}
+                    ("client_max_window_bits", Some(bits)) => {
+                        bits.parse::<u8>().ok().filter(|b| (8..=15).contains(b))?;
+                    }
+                    ("server_max_window_bits", Some("15")) => {}
+                    _ => return None,
+                }
+            }
+            Some(params)
+        })
+    }
+}
+
+impl std::fmt::Display for DeflateParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(DEFLATE)?;
+        if self.server_no_context_takeover {
+            f.write_str("; server_no_context_takeover")?;
+        }
+        Ok(())
+    }
+}
+
+// Raw deflate both ways, keeping the window between messages unless the
+// client asked the server not to.
+struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+    reset: bool,
+}
+
+impl Deflate {
+    fn new(params: DeflateParams) -> Self {
+        Deflate {
+            compress: Compress::new(Compression::fast(), false),
+            decompress: Decompress::new(false),
+            reset: params.server_no_context_takeover,
+        }
+    }
+
+    fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let read = (self.compress.total_in() - start) as usize;
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity());
+            }
+            self.compress
+                .compress_vec(&data[read..], &mut out, FlushCompress::Sync)
+                .context("Compressing a message")?;
+            // Flushed once there is room left over and nothing left in.
+            let read = (self.compress.total_in() - start) as usize;
+            if read == data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if out.ends_with(&DEFLATE_TAIL) {
+            out.truncate(out.len() - DEFLATE_TAIL.len());
+        }
+        if self.reset {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+
+    /// `None` when the message would grow past `limit`.
+    fn decompress(&mut self, data: &[u8], limit: usize) -> Result<Option<Vec<u8>>> {
+        let input = [data, &DEFLATE_TAIL[..]].concat();
+        let mut out = Vec::with_capacity((data.len() * 2).min(limit) + 64);
+        let start = self.decompress.total_in();
+        loop {
+            let read = (self.decompress.total_in() - start) as usize;
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity());
+            }
+            let before = (self.decompress.total_in(), self.decompress.total_out());
+            self.decompress
+                .decompress_vec(&input[read..], &mut out, FlushDecompress::Sync)
+                .context("Decompressing a message")?;
+            if out.len() > limit {
+                return Ok(None);
+            }
+            let read = (self.decompress.total_in() - start) as usize;
+            if read == input.len() && out.len() < out.capacity() {
+                break;
+            }
+            if before == (self.decompress.total_in(), self.decompress.total_out())
+                && out.len() < out.capacity()
+            {
+                return Err(Error::GeneralError(
+                    "Truncated compressed message".to_string(),
+                ));
+            }
+        }
+        Ok(Some(out))
+    }
+}
+
+// A message whose continuation frames are still to come.
+struct Partial {
+    text: bool,
+    compressed: bool,
+    data: BytesMut,
+}
+
+struct Frame {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    payload: Bytes,
+}
+
+/// One WebSocket connection, server side. Messages split over several
+/// frames are put back together, pings are answered and a close is
+/// returned, never sent, by `recv`. Waiting in `recv` is cancel safe, so it
+/// can be raced against messages the server pushes.
+pub struct WebSocket {
+    stream: Upgraded,
+    buffered: BytesMut,
+    partial: Option<Partial>,
+    deflate: Option<Deflate>,
+    max_message: usize,
+    // A close frame went out; nothing else may follow it.
+    closing: bool,
+    // The peer's close came in, or the connection failed.
+    closed: bool,
+}
+
+impl WebSocket {
+    fn new(stream: Upgraded, deflate: Option<Deflate>) -> Self {
+        WebSocket {
+            stream,
+            buffered: BytesMut::new(),
+            partial: None,
+            deflate,
+            max_message: 16 * 1024 * 1024,
+            closing: false,
+            closed: false,
+        }
+    }
+
+    /// The handshake request.
+    pub fn request(&self) -> &Arc<Request> {
+        self.stream.request()
+    }
+
+    /// Longest message accepted, decompressed; larger ones close the
+    /// connection with 1009.
+    pub fn set_max_message(&mut self, bytes: usize) {
+        self.max_message = bytes;
+    }
+
+    /// The next message, `None` once the connection is closed. A peer
+    /// breaking the protocol is sent the matching close code, and the
+    /// connection fails with an error.
+    pub async fn recv(&mut self) -> Result<Option<Message>> {
+        while !self.closed {
+            let frame = match self.parse_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    self.buffered.reserve(READ_CHUNK);
+                    let read = self.stream.read_buf(&mut self.buffered).await;
+                    if !matches!(read, Ok(n) if n > 0) {
+                        self.closed = true;
+                    }
+                    continue;
+                }
+                Err(code) => return Err(self.fail(code).await),
+            };
+            match self.message(frame) {
+                Ok(Some(message)) => return self.answer(message).await.map(Some),
+                Ok(None) => {}
+                Err(code) => return Err(self.fail(code).await),
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        match message {
+            Message::Text(text) => self.send_data(TEXT, text.as_bytes()).await,
+            Message::Binary(data) => self.send_data(BINARY, &data).await,
+            Message::Ping(data) => {
+                self.send_frame(PING, false, &data[..data.len().min(MAX_CONTROL)])
+                    .await
+            }
+            Message::Pong(data) => {
+                self.send_frame(PONG, false, &data[..data.len().min(MAX_CONTROL)])
+                    .await
+            }
+            Message::Close(Some(frame)) => self.close(frame.code, &frame.reason).await,
+            Message::Close(None) => self.close(CloseCode::NORMAL, "").await,
+        }
+    }
+
+    /// Starts the closing handshake; `recv` returns the peer's close.
+    pub async fn close(&mut self, code: CloseCode, reason: &str) -> Result<()> {
+        // The reason has to fit a control frame, on a character boundary.
+        let mut end = reason.len().min(MAX_CONTROL - 2);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut payload = code.0.to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        self.send_frame(CLOSE, false, &payload).await?;
+        self.closing = true;
+        Ok(())
+    }
+
+    async fn send_data(&mut self, opcode: u8, data: &[u8]) -> Result<()> {
+        match &mut self.deflate {
+            Some(deflate) if data.len() >= MIN_COMPRESSED => {
+                let compressed = deflate.compress(data)?;
+                self.send_frame(opcode, true, &compressed).await
+            }
+            _ => self.send_frame(opcode, false, data).await,
+        }
+    }
+
+    async fn send_frame(&mut self, opcode: u8, compressed: bool, payload: &[u8]) -> Result<()> {
+        if self.closing {
+            return Err(Error::GeneralError("WebSocket already closed".to_string()));
+        }
+        let mut head = Vec::with_capacity(10);
+        head.push(FIN | if compressed { RSV1 } else { 0 } | opcode);
+        match payload.len() {
+            len @ 0..=125 => head.push(len as u8),
+            len @ 126..=0xffff => {
+                head.push(126);
+                head.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                head.push(127);
+                head.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        self.stream.write_all(&head).await.context("Frame head")?;
+        self.stream
+            .write_all(payload)
+            .await
+            .context("Frame payload")?;
+        self.stream.flush().await.context("Flushing a frame")
+    }
+
+    // Replies a control frame asks for.
+    async fn answer(&mut self, message: Message) -> Result<Message> {
+        match &message {
+            Message::Ping(data) if !self.closing => {
+                self.send_frame(PONG, false, data).await?;
+            }
+            Message::Close(frame) => {
+                self.closed = true;
+                if !self.closing {
+                    let code = frame.as_ref().map_or(CloseCode::NORMAL, |f| f.code);
+                    self.close(code, "").await?;
+                }
+                let _ = self.stream.shutdown().await;
+            }
+            _ => {}
+        }
+        Ok(message)
+    }
+
+    async fn fail(&mut self, code: CloseCode) -> Error {
+        self.closed = true;
+        if !self.closing {
+            let _ = self.close(code, "").await;
+        }
+        let _ = self.stream.shutdown().await;
+        Error::GeneralError(format!("WebSocket closed with {}", code.0))
+    }
+
+    // A whole frame off the front of the buffer, unmasked.
+    fn parse_frame(&mut self) -> std::result::Result<Option<Frame>, CloseCode> {
+        let buf = &self.buffered[..];
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let (b0, b1) = (buf[0], buf[1]);
+        // Clients mask every frame, and no extension here uses RSV2 or 3.
+        if b1 & MASKED == 0 || b0 & RSV2_3 != 0 {
+            return Err(CloseCode::PROTOCOL_ERROR);
+        }
+        let (len, offset) = match b1 & 0x7f {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() >= 10 => {
+                let len = u64::from_be_bytes(buf[2..10].try_into().unwrap_or_default());
+                (len, 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+        if len > self.max_message as u64 {
+            return Err(CloseCode::MESSAGE_TOO_BIG);
+        }
+        let len = len as usize;
+        if buf.len() < offset + 4 + len {
+            return Ok(None);
+        }
+        let mask = [
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ];
+        self.buffered.advance(offset + 4);
+        let mut payload = self.buffered.split_to(len);
+        payload
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b ^= mask[i % 4]);
+        Ok(Some(Frame {
+            fin: b0 & FIN != 0,
+            rsv1: b0 & RSV1 != 0,
+            opcode: b0 & 0x0f,
+            payload: payload.freeze(),
+        }))
+    }
+
+    // What a frame adds up to: a message, or nothing until the last piece.
+    fn message(&mut self, frame: Frame) -> std::result::Result<Option<Message>, CloseCode> {
+        let Frame {
+            fin,
+            rsv1,
+            opcode,
+            payload,
+        } = frame;
+        if opcode >= CLOSE {
+            if !fin || rsv1 || payload.len() > MAX_CONTROL {
+                return Err(CloseCode::PROTOCOL_ERROR);
+            }
+            return match opcode {
+                PING => Ok(Some(Message::Ping(payload))),
+                PONG => Ok(Some(Message::Pong(payload))),
+                CLOSE => close_frame(&payload).map(Message::Close).map(Some),
+                _ => Err(CloseCode::PROTOCOL_ERROR),
+            };
+        }
+        let mut partial = match (opcode, self.partial.take()) {
+            (CONTINUATION, Some(partial)) if !rsv1 => partial,
+            (TEXT | BINARY, None) if !rsv1 || self.deflate.is_some() => Partial {
+                text: opcode == TEXT,
+                compressed: rsv1,
+                data: BytesMut::new(),
+            },
+            _ => return Err(CloseCode::PROTOCOL_ERROR),
+        };
+        if partial.data.len() + payload.len() > self.max_message {
+            return Err(CloseCode::MESSAGE_TOO_BIG);
+        }
+        partial.data.extend_from_slice(&payload);
+        if !fin {
+            self.partial = Some(partial);
+            return Ok(None);
+        }
+        let data = match (partial.compressed, &mut self.deflate) {
+            (true, Some(deflate)) => match deflate.decompress(&partial.data, self.max_message) {
+                Ok(Some(data)) => Bytes::from(data),
+                Ok(None) => return Err(CloseCode::MESSAGE_TOO_BIG),
+                Err(_) => return Err(CloseCode::INVALID_DATA),
+            },
+            _ => partial.data.freeze(),
+        };
+        match partial.text {
+            true => String::from_utf8(data.to_vec())
+                .map(|text| Some(Message::Text(text)))
+                .map_err(|_| CloseCode::INVALID_DATA),
+            false => Ok(Some(Message::Binary(data))),
+        }
+    }
+}
+
+fn close_frame(payload: &[u8]) -> std::result::Result<Option<CloseFrame>, CloseCode> {
+    match payload {
+        [] => Ok(None),
+        [_] => Err(CloseCode::PROTOCOL_ERROR),
+        [hi, lo, reason @ ..] => {
+            let code = CloseCode(u16::from_be_bytes([*hi, *lo]));
+            if !code.is_valid() {
+                return Err(CloseCode::PROTOCOL_ERROR);
+            }
+            let reason = std::str::from_utf8(reason).map_err(|_| CloseCode::INVALID_DATA)?;
+            Ok(Some(CloseFrame {
+                code,
+                reason: reason.to_string(),
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_request, route, serve_connection, ConnectionInfo, State};
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    async fn echo(mut socket: WebSocket) -> Result<()> {
+        while let Some(message) = socket.recv().await? {
+            match message {
+                Message::Text(_) | Message::Binary(_) => socket.send(message).await?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn serve(server: DuplexStream) -> tokio::task::JoinHandle<Result<()>> {
+        let f = Arc::new(|s: State| {
+            let routes = route::get("/ws").set_switch(websocket(echo));
+            std::future::ready(routes.handle(s).map(|(s, _)| s))
+        });
+        let info = ConnectionInfo::new(None, None);
+        tokio::spawn(serve_connection(server, info, f, Default::default()))
+    }
+
+    // A client frame, masked as clients must.
+    fn frame(b0: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![b0];
+        match payload.len() {
+            len @ 0..=125 => frame.push(MASKED | len as u8),
+            len => {
+                frame.push(MASKED | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    async fn read_frame(stream: &mut DuplexStream) -> Result<(u8, Vec<u8>)> {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).await.context("head")?;
+        let len = match head[1] {
+            126 => stream.read_u16().await.context("length")? as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.context("payload")?;
+        Ok((head[0], payload))
+    }
+
+    async fn open(client: &mut DuplexStream, extensions: &str) -> Result<String> {
+        let request = format!(
+            "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n{extensions}\r\n"
+        );
+        client
+            .write_all(request.as_bytes())
+            .await
+            .context("write")?;
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.context("read")?);
+        }
+        Ok(String::from_utf8_lossy(&head).to_string())
+    }
+
+    #[test]
+    fn test_handshake() -> Result<()> {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        let status = |req: &[u8]| -> Result<StatusCode> {
+            let resp = match handshake(&parse_request(req)?) {
+                Ok((resp, _)) => resp,
+                Err(resp) => resp,
+            };
+            let code = resp.borrow().0.status_code();
+            Ok(code)
+        };
+        let upgrade = "Upgrade: websocket\r\nConnection: Upgrade\r\n";
+        let plain = b"GET /ws HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let old = format!(
+            "GET /ws HTTP/1.1\r\nHost: localhost\r\n{upgrade}Sec-WebSocket-Version: 8\r\n\r\n"
+        );
+        let keyless = format!(
+            "GET /ws HTTP/1.1\r\nHost: localhost\r\n{upgrade}Sec-WebSocket-Version: 13\r\n\r\n"
+        );
+        assert_eq!(status(plain)?, StatusCode::SC426);
+        assert_eq!(status(old.as_bytes())?, StatusCode::SC426);
+        assert_eq!(status(keyless.as_bytes())?, StatusCode::SC400);
+
+        let offer = |offers: &str| DeflateParams::negotiate(offers).map(|p| p.to_string());
+        let agreed = Some(DEFLATE.to_string());
+        assert_eq!(offer("permessage-deflate; client_max_window_bits"), agreed);
+        assert_eq!(offer("permessage-deflate; server_max_window_bits=10"), None);
+        assert_eq!(
+            offer("permessage-deflate; server_max_window_bits=10, permessage-deflate"),
+            agreed
+        );
+        assert_eq!(
+            offer("permessage-deflate; server_no_context_takeover").as_deref(),
+            Some("permessage-deflate; server_no_context_takeover")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_echo() -> Result<()> {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let serving = serve(server);
+        let head = open(&mut client, "").await?;
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(!head.contains("Sec-WebSocket-Extensions"));
+
+        // A text message in two pieces, with a ping between them.
+        client
+            .write_all(&frame(TEXT, b"Hel"))
+            .await
+            .context("write")?;
+        client
+            .write_all(&frame(FIN | PING, b"?"))
+            .await
+            .context("write")?;
+        client
+            .write_all(&frame(FIN, b"lo"))
+            .await
+            .context("write")?;
+        assert_eq!(read_frame(&mut client).await?, (FIN | PONG, b"?".to_vec()));
+        assert_eq!(
+            read_frame(&mut client).await?,
+            (FIN | TEXT, b"Hello".to_vec())
+        );
+        let large = vec![7; 300];
+        client
+            .write_all(&frame(FIN | BINARY, &large))
+            .await
+            .context("write")?;
+        assert_eq!(read_frame(&mut client).await?, (FIN | BINARY, large));
+
+        client
+            .write_all(&frame(FIN | CLOSE, &[0x03, 0xe8]))
+            .await
+            .context("write")?;
+        assert_eq!(
+            read_frame(&mut client).await?,
+            (FIN | CLOSE, vec![0x03, 0xe8])
+        );
+        serving.await.context("join")??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_protocol_errors() -> Result<()> {
+        let cases: [(Vec<u8>, u16); 3] = [
+            (frame(FIN | TEXT, &[0xff, 0xfe]), 1007),
+            (frame(FIN, b"stray continuation"), 1002),
+            (vec![FIN | TEXT, 0x02, b'h', b'i'], 1002),
+        ];
+        for (bad, code) in cases {
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            let serving = serve(server);
+            open(&mut client, "").await?;
+            client.write_all(&bad).await.context("write")?;
+            let (b0, payload) = read_frame(&mut client).await?;
+            assert_eq!((b0, &payload[..2]), (FIN | CLOSE, &code.to_be_bytes()[..]));
+            assert!(serving.await.context("join")?.is_err());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_permessage_deflate() -> Result<()> {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let serving = serve(server);
+        let offer = "Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n";
+        let head = open(&mut client, offer).await?;
+        assert!(head.contains("Sec-WebSocket-Extensions: permessage-deflate\r\n"));
+
+        // The client's side of the same extension, contexts kept throughout.
+        let mut deflate = Deflate::new(DeflateParams {
+            server_no_context_takeover: false,
+        });
+        let text = "compress me, ".repeat(20);
+        for _ in 0..2 {
+            let compressed = deflate.compress(text.as_bytes())?;
+            let message = frame(FIN | RSV1 | TEXT, &compressed);
+            client.write_all(&message).await.context("write")?;
+            let (b0, payload) = read_frame(&mut client).await?;
+            assert_eq!(b0, FIN | RSV1 | TEXT);
+            assert!(payload.len() < text.len());
+            let echoed = deflate.decompress(&payload, usize::MAX)?;
+            assert_eq!(echoed.as_deref(), Some(text.as_bytes()));
+        }
+        drop(client);
+        serving.await.context("join")??;
+        Ok(())
+    }
+}